@@ -0,0 +1,6117 @@
+//! Client library for building Cargo workspaces on a remote Tesseract server.
+
+use anyhow::{Context, Result};
+use cargo_metadata::{Metadata, MetadataCommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use colored::*;
+use flate2::{write::GzEncoder, Compression};
+use fs2::FileExt;
+use futures::future::try_join_all;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use notify::Watcher;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+use tar::Builder;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{Mutex, Semaphore},
+};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+const ZSTD_LEVEL: i32 = 3;
+const ZSTD_DICT_SAMPLES: usize = 10;
+const ZSTD_DICT_MAX_SIZE: usize = 16 * 1024;
+const KEYRING_SERVICE: &str = "cargo-tesseract";
+const SSH_TUNNEL_POLL_ATTEMPTS: u32 = 40;
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+const WATCH_EXCLUDE_DEFAULTS: [&str; 6] = ["target{,/**}", ".git{,/**}", ".idea{,/**}", "*.log", "*.swp", "*.DS_Store"];
+const EXTRA_INCLUDE_DIR_WARN_SIZE: u64 = 10 * 1024 * 1024;
+const BUILD_HISTORY_LEN: usize = 10;
+const DAEMON_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+tokio::task_local! {
+    /// Set while the daemon serves a build, so its output goes to the invoking client
+    static FORWARDED_OUTPUT: tokio::sync::mpsc::UnboundedSender<DaemonResponse>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+fn write_output(stream: OutputStream, text: String) {
+    if let Ok(client) = FORWARDED_OUTPUT.try_with(Clone::clone) {
+        // A client that hung up has its build cancelled, so its output can be dropped
+        let _ = client.send(DaemonResponse::Output { stream, text });
+        return;
+    }
+    match stream {
+        OutputStream::Stdout => print!("{}", text),
+        OutputStream::Stderr => eprint!("{}", text),
+    }
+}
+
+/// Like `println!`, but reaches the invoking client of a daemon-served build
+macro_rules! outln {
+    () => {
+        write_output(OutputStream::Stdout, "\n".to_string())
+    };
+    ($($arg:tt)*) => {
+        write_output(OutputStream::Stdout, format!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// Like `eprintln!`, but reaches the invoking client of a daemon-served build
+macro_rules! errln {
+    ($($arg:tt)*) => {
+        write_output(OutputStream::Stderr, format!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// Log writer for stderr that follows daemon-served builds to their client like [`errln!`]
+pub fn log_writer() -> impl Write {
+    struct LogWriter;
+    impl Write for LogWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            write_output(OutputStream::Stderr, String::from_utf8_lossy(buf).into_owned());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            std::io::stderr().flush()
+        }
+    }
+    LogWriter
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "cargo")]
+#[command(bin_name = "cargo")]
+pub enum Cargo {
+    #[command(name = "tess")]
+    Tesseract(CliArgs),
+}
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
+pub struct CliArgs {
+    // Not sent to the daemon, which only serves plain builds
+    #[command(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+
+    /// Server address (host:port); give several to fail over between them
+    #[arg(short, long, required_unless_present = "dry_run", env = "TESSERACT_SERVER", value_delimiter = ',')]
+    pub server: Vec<String>,
+
+    /// Auth token for the server (defaults to the one stored by `cargo tess login`)
+    #[arg(long, env = "TESSERACT_AUTH_TOKEN", hide_env_values = true)]
+    pub auth_token: Option<String>,
+
+    /// Build in release mode
+    #[arg(short, long, env = "TESSERACT_RELEASE", value_parser = clap::builder::BoolishValueParser::new())]
+    pub release: bool,
+
+    /// Target triple (e.g., x86_64-pc-windows-msvc)
+    #[arg(short, long, env = "TESSERACT_TARGET")]
+    pub target: Option<String>,
+
+    /// Enable debug logging
+    #[arg(short, long, env = "TESSERACT_DEBUG", value_parser = clap::builder::BoolishValueParser::new())]
+    pub debug: bool,
+
+    /// Number of retry attempts for failed builds
+    #[arg(short = 'n', long, default_value = "3", env = "TESSERACT_RETRIES")]
+    pub retries: u32,
+
+    /// Maximum number of packages built concurrently against the server
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..), env = "TESSERACT_PARALLEL_PACKAGES")]
+    pub parallel_packages: u32,
+
+    /// Number of parallel jobs for the server-side cargo invocation
+    #[arg(long, env = "TESSERACT_JOBS")]
+    pub server_jobs: Option<u32>,
+
+    /// Compress source tarballs with a zstd dictionary trained on previous builds
+    #[arg(long, env = "TESSERACT_ZSTD_DICT", value_parser = clap::builder::BoolishValueParser::new())]
+    pub zstd_dict: bool,
+
+    /// Print detailed transfer statistics
+    #[arg(short, long, env = "TESSERACT_VERBOSE", value_parser = clap::builder::BoolishValueParser::new())]
+    pub verbose: bool,
+
+    /// Don't print server build output; only show progress (output is still shown on failure)
+    #[arg(long, env = "TESSERACT_HIDE_OUTPUT", value_parser = clap::builder::BoolishValueParser::new())]
+    pub hide_output: bool,
+
+    /// Append server build output to this file
+    #[arg(long, env = "TESSERACT_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Maximum compressed source tarball size in MB (0 disables the limit)
+    #[arg(long, default_value = "512", env = "TESSERACT_MAX_TARBALL_SIZE_MB")]
+    pub max_tarball_size_mb: u64,
+
+    /// Build locally with cargo if the server can't be reached
+    #[arg(long, env = "TESSERACT_FALLBACK_LOCAL", value_parser = clap::builder::BoolishValueParser::new())]
+    pub fallback_local: bool,
+
+    /// Have the server record cargo build timings and download the HTML report
+    #[arg(long, env = "TESSERACT_TIMINGS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub timings: bool,
+
+    /// Open the timing report in a browser after the build (requires --timings)
+    #[arg(long, requires = "timings", env = "TESSERACT_REPORT_OPEN", value_parser = clap::builder::BoolishValueParser::new())]
+    pub report_open: bool,
+
+    /// Rebuild whenever workspace files change
+    #[arg(short, long, env = "TESSERACT_WATCH", value_parser = clap::builder::BoolishValueParser::new())]
+    pub watch: bool,
+
+    /// Upload a precompiled rlib and pass it to rustc as --extern (repeatable)
+    #[arg(long = "extern", value_name = "NAME=PATH", value_parser = parse_extern, env = "TESSERACT_EXTERNS")]
+    pub externs: Vec<(String, PathBuf)>,
+
+    /// Only check that the client and server versions are compatible, then exit
+    #[arg(long, conflicts_with = "skip_version_check")]
+    pub version_check: bool,
+
+    /// Don't compare client and server versions before building
+    #[arg(long, env = "TESSERACT_SKIP_VERSION_CHECK", value_parser = clap::builder::BoolishValueParser::new())]
+    pub skip_version_check: bool,
+
+    /// Strip this leading path from artifact paths sent by the server
+    #[arg(long, value_name = "PREFIX", env = "TESSERACT_STRIP_ARTIFACT_PREFIX")]
+    pub strip_artifact_prefix: Option<PathBuf>,
+
+    /// Prepend this path to artifact paths sent by the server
+    #[arg(long, value_name = "PREFIX", env = "TESSERACT_PREPEND_ARTIFACT_PREFIX")]
+    pub prepend_artifact_prefix: Option<PathBuf>,
+
+    /// Don't build packages with `publish = false`
+    #[arg(long, conflicts_with = "only_unpublishable", env = "TESSERACT_SKIP_UNPUBLISHABLE", value_parser = clap::builder::BoolishValueParser::new())]
+    pub skip_unpublishable: bool,
+
+    /// Only build packages with `publish = false`
+    #[arg(long, env = "TESSERACT_ONLY_UNPUBLISHABLE", value_parser = clap::builder::BoolishValueParser::new())]
+    pub only_unpublishable: bool,
+
+    /// List the files that would be uploaded, then exit without building
+    #[arg(long)]
+    pub print_tarball_contents: bool,
+
+    /// Treat warnings as errors (passes `-D warnings` to rustc and fails on any warning)
+    #[arg(long, env = "TESSERACT_DENY_WARNINGS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub deny_warnings: bool,
+
+    /// How to name downloaded artifacts
+    #[arg(long, value_enum, default_value = "server", env = "TESSERACT_OUTPUT_FORMAT")]
+    pub output_format: OutputFormat,
+
+    /// Include integration tests and examples in the upload (implied by `cargo tess test`)
+    #[arg(long, env = "TESSERACT_INCLUDE_TESTS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub include_tests: bool,
+
+    /// How to display compiler diagnostics from the server
+    #[arg(long, value_enum, default_value = "human", env = "TESSERACT_ERROR_FORMAT")]
+    pub error_format: ErrorFormat,
+
+    /// How many times to reconnect to an in-progress build after the connection drops
+    #[arg(long, default_value = "3", env = "TESSERACT_RECONNECT_ATTEMPTS")]
+    pub reconnect_attempts: u32,
+
+    /// Seconds to wait for another cargo tess process to finish writing artifacts
+    #[arg(long, default_value = "30", env = "TESSERACT_LOCK_TIMEOUT_SECS")]
+    pub lock_timeout_secs: u64,
+
+    /// Reach the server through an SSH port forward via this host
+    #[arg(long, value_name = "USER@HOST", env = "TESSERACT_SSH_TUNNEL")]
+    pub ssh_tunnel: Option<String>,
+
+    /// Hash algorithm used to verify downloaded artifacts
+    #[arg(long, value_enum, default_value = "sha256", env = "TESSERACT_ARTIFACT_HASH_ALGORITHM")]
+    pub artifact_hash_algorithm: HashAlgorithm,
+
+    /// Cap the lint level of non-workspace dependencies (cargo caps them at warn by default)
+    #[arg(long, value_name = "LEVEL", value_parser = ["allow", "warn", "deny", "forbid"], env = "TESSERACT_CAP_LINTS")]
+    pub cap_lints: Option<String>,
+
+    /// Upload artifacts to an S3-compatible bucket after a successful build
+    #[arg(long, value_name = "s3://BUCKET/PREFIX", value_parser = S3Destination::parse, env = "TESSERACT_UPLOAD_ARTIFACTS_TO_S3")]
+    pub upload_artifacts_to_s3: Option<S3Destination>,
+
+    /// Make artifacts uploaded to S3 publicly readable
+    #[arg(long, requires = "upload_artifacts_to_s3", env = "TESSERACT_S3_PUBLIC", value_parser = clap::builder::BoolishValueParser::new())]
+    pub s3_public: bool,
+
+    /// Comma-separated list of features to activate
+    #[arg(short = 'F', long, value_delimiter = ',', env = "TESSERACT_FEATURES")]
+    pub features: Vec<String>,
+
+    /// Write a <artifact>.provenance.json record of the build inputs next to each artifact
+    #[arg(long, env = "TESSERACT_TRACE_ARTIFACT_PROVENANCE", value_parser = clap::builder::BoolishValueParser::new())]
+    pub trace_artifact_provenance: bool,
+
+    /// Without --target, build for every target listed in rust-toolchain.toml
+    #[arg(long = "auto-target", env = "TESSERACT_AUTO_TARGET", value_parser = clap::builder::BoolishValueParser::new())]
+    pub auto_detect_targets: bool,
+
+    /// Reuse the last uploaded tarball when the source tree is unchanged (always on in watch mode)
+    #[arg(long, env = "TESSERACT_NO_TARBALL_REBUILD", value_parser = clap::builder::BoolishValueParser::new())]
+    pub no_tarball_rebuild: bool,
+
+    /// Exclude targets of this kind from the build (repeatable)
+    #[arg(long, value_enum, value_name = "KIND", env = "TESSERACT_IGNORE_KIND", value_delimiter = ',')]
+    pub ignore_kind: Vec<TargetKind>,
+
+    /// Directory outside the workspace to include in the tarball under extra/<name> (repeatable)
+    #[arg(long, value_name = "PATH", env = "TESSERACT_EXTRA_INCLUDE_DIR", value_delimiter = ',')]
+    pub extra_include_dir: Vec<PathBuf>,
+
+    /// Build these standard library crates from source (-Z build-std, needs a nightly server)
+    #[arg(long, value_name = "COMPONENTS", value_delimiter = ',', value_parser = ["core", "std", "alloc", "panic_abort", "panic_unwind"], env = "TESSERACT_BUILD_STD")]
+    pub build_std: Vec<String>,
+
+    /// Also download the make-style .d dependency files next to the artifacts
+    #[arg(long, env = "TESSERACT_EMIT_DEP_INFO", value_parser = clap::builder::BoolishValueParser::new())]
+    pub emit_dep_info: bool,
+
+    /// Write artifacts to target/<profile>/<package>-<version>/ so older versions are kept
+    #[arg(long, env = "TESSERACT_VERSIONED_ARTIFACTS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub versioned_artifacts: bool,
+
+    /// Show an ETA based on the last builds of each package instead of a spinner
+    #[arg(long, env = "TESSERACT_ETA", value_parser = clap::builder::BoolishValueParser::new())]
+    pub eta: bool,
+
+    /// Rewrite FROM to TO in debug info and panic paths (repeatable)
+    #[arg(long, value_name = "FROM=TO", value_parser = parse_remap_path_prefix, env = "TESSERACT_REMAP_PATH_PREFIX", value_delimiter = ',')]
+    pub remap_path_prefix: Vec<String>,
+
+    /// Remap the server's build directory to the local workspace root
+    #[arg(long, env = "TESSERACT_AUTO_REMAP", value_parser = clap::builder::BoolishValueParser::new())]
+    pub auto_remap: bool,
+
+    /// Pass --check-cfg=<SPEC> to rustc (repeatable; ignored with a warning before Rust 1.80)
+    #[arg(long, value_name = "SPEC", env = "TESSERACT_CHECK_CFG")]
+    pub check_cfg: Vec<String>,
+
+    /// Check cfgs against each package's declared features
+    #[arg(long, env = "TESSERACT_CHECK_CFG_WELL_KNOWN", value_parser = clap::builder::BoolishValueParser::new())]
+    pub check_cfg_well_known: bool,
+
+    /// Only pack the workspace itself (default)
+    #[arg(long, overrides_with = "include_path_deps", env = "TESSERACT_WORKSPACE_ONLY", value_parser = clap::builder::BoolishValueParser::new())]
+    pub workspace_only: bool,
+
+    /// Also pack path dependencies outside the workspace under path-deps/<name>
+    #[arg(long, overrides_with = "workspace_only", env = "TESSERACT_INCLUDE_PATH_DEPS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub include_path_deps: bool,
+
+    /// Have the server kill the build if it uses more than this much memory
+    #[arg(long, value_name = "MB", env = "TESSERACT_MEMORY_LIMIT_MB")]
+    pub memory_limit_mb: Option<u64>,
+
+    /// Server-side --target-dir, e.g. a RAM disk; the server needs write access to it
+    #[arg(long, value_name = "PATH", env = "TESSERACT_TARGET_DIR_SERVER")]
+    pub target_dir_server: Option<String>,
+
+    /// Annotate output for a CI system [default: detected from GITHUB_ACTIONS / GITLAB_CI]
+    #[arg(long, value_enum, env = "TESSERACT_COLOR_SCHEME")]
+    pub color_scheme: Option<ColorScheme>,
+
+    /// Incremental compilation on the server [default: false for --release, auto otherwise]
+    #[arg(long, value_enum, value_name = "MODE", env = "TESSERACT_INCREMENTAL")]
+    pub incremental: Option<Incremental>,
+
+    /// Shorthand for --incremental false
+    #[arg(long, conflicts_with = "incremental", env = "TESSERACT_NO_INCREMENTAL", value_parser = clap::builder::BoolishValueParser::new())]
+    pub no_incremental: bool,
+
+    /// Compress the tarball on all cores (pigz for gzip, multithreaded zstd)
+    #[arg(long, env = "TESSERACT_PARALLEL_TARBALL_COMPRESS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub parallel_tarball_compress: bool,
+
+    /// Fail the build if cargo-udeps (or cargo-machete) finds unused dependencies
+    #[arg(long, env = "TESSERACT_ERROR_ON_UNUSED_DEPS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub error_on_unused_deps: bool,
+
+    /// Save each uploaded source tarball to this file, or to <DIR>/<package>.tar.gz if a directory
+    #[arg(long, value_name = "PATH", env = "TESSERACT_SOURCE_ARCHIVE")]
+    pub source_archive: Option<PathBuf>,
+
+    /// Create the source tarballs without contacting the server
+    #[arg(long, env = "TESSERACT_DRY_RUN", value_parser = clap::builder::BoolishValueParser::new())]
+    pub dry_run: bool,
+
+    /// Environment the server's cargo sees: allow-all, deny-all, allow:VAR,... or deny:VAR,...
+    #[arg(long, value_name = "FILTER", default_value = "allow-all", value_parser = EnvFilter::parse, env = "TESSERACT_ENV_FILTER")]
+    pub env_filter: EnvFilter,
+
+    /// Use the sparse protocol for crates.io on the server (needs Cargo 1.68+)
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set, env = "TESSERACT_SPARSE_REGISTRY", value_parser = clap::builder::BoolishValueParser::new())]
+    pub sparse_registry: bool,
+
+    /// Don't rebuild in watch mode when only paths matching this glob change (repeatable)
+    #[arg(long, value_name = "GLOB", env = "TESSERACT_WATCH_EXCLUDE", value_delimiter = ',')]
+    pub watch_exclude: Vec<String>,
+
+    /// Run the built binary afterwards, passing it any arguments after `--`
+    #[arg(long, visible_alias = "exec", env = "TESSERACT_RUN", value_parser = clap::builder::BoolishValueParser::new())]
+    pub run: bool,
+
+    /// Only build this binary target (picks the binary for --run)
+    #[arg(long, value_name = "NAME", env = "TESSERACT_BIN")]
+    pub bin: Option<String>,
+
+    /// Maximum number of build connections open to the server at once
+    #[arg(long, value_name = "N", default_value = "4", value_parser = clap::value_parser!(u32).range(1..), env = "TESSERACT_MAX_CONCURRENT_CONNECTIONS")]
+    pub max_concurrent_connections: u32,
+
+    /// Pin the server's dependency resolution to the versions in the local Cargo.lock
+    #[arg(long, env = "TESSERACT_PIN_DEPS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub pin_deps: bool,
+
+    /// Set an environment variable for the server's build, e.g. for build.rs (repeatable)
+    #[arg(long, visible_alias = "env", value_name = "KEY=VALUE", value_parser = parse_key_value, env = "TESSERACT_BUILD_METADATA", value_delimiter = ',')]
+    pub build_metadata: Vec<(String, String)>,
+
+    /// Also set GIT_COMMIT, GIT_BRANCH, BUILD_TIMESTAMP and CARGO_TESSERACT_VERSION
+    #[arg(long, env = "TESSERACT_AUTO_BUILD_METADATA", value_parser = clap::builder::BoolishValueParser::new())]
+    pub auto_build_metadata: bool,
+
+    /// Write a sha256sum-style manifest of the built artifacts to this file
+    #[arg(long, value_name = "PATH", env = "TESSERACT_CHECKSUM_FILE")]
+    pub checksum_file: Option<PathBuf>,
+
+    /// Give up once the server's rate limit has delayed a package this long in total
+    #[arg(long, value_name = "N", default_value = "300", env = "TESSERACT_MAX_RATE_LIMIT_WAIT_SECS")]
+    pub max_rate_limit_wait_secs: u32,
+
+    /// When workspace packages depend on each other in a cycle, print the cycle and the Cargo.toml lines causing it
+    #[arg(long, env = "TESSERACT_REPORT_CYCLE_DETAILS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub report_cycle_details: bool,
+
+    /// Build the library as this crate type instead of the one in Cargo.toml (uses `cargo rustc --crate-type`)
+    #[arg(long, value_name = "TYPE", value_parser = ["bin", "lib", "rlib", "dylib", "cdylib", "staticlib", "proc-macro"], env = "TESSERACT_CRATE_TYPE")]
+    pub crate_type: Option<String>,
+
+    /// Before uploading, list the files that changed since the last successful build
+    #[arg(long, env = "TESSERACT_DIFF_FROM_LAST", value_parser = clap::builder::BoolishValueParser::new())]
+    pub diff_from_last: bool,
+
+    /// Remove local artifact cache entries unused for this many days (0 keeps them forever)
+    #[arg(long, value_name = "N", default_value = "30", env = "TESSERACT_MAX_ARTIFACT_AGE_DAYS")]
+    pub max_artifact_age_days: u64,
+
+    /// Ask the server to keep its workspace when a build fails, for inspection over SSH
+    #[arg(long, env = "TESSERACT_NO_CLEAN_ON_ERROR", value_parser = clap::builder::BoolishValueParser::new())]
+    pub no_clean_on_error: bool,
+
+    /// Emit debug info separately (.pdb, .dSYM or .dwp), written next to the artifact it belongs to
+    #[arg(long, value_enum, value_name = "MODE", env = "TESSERACT_SPLIT_DEBUGINFO")]
+    pub split_debuginfo: Option<SplitDebuginfo>,
+
+    /// CPU features for codegen, e.g. +avx2,+bmi2 or -sse2 (passed as `-C target-feature`)
+    #[arg(long, value_name = "FEATURES", value_parser = parse_target_feature, value_delimiter = ',', env = "TESSERACT_TARGET_FEATURES")]
+    pub target_features: Vec<String>,
+
+    /// Have the server stream artifacts in chunks so a dropped connection resumes the download
+    #[arg(long, env = "TESSERACT_RESUMABLE_DOWNLOAD", value_parser = clap::builder::BoolishValueParser::new())]
+    pub resumable_download: bool,
+
+    /// How many times to resume an interrupted artifact download, with exponential backoff
+    #[arg(long, value_name = "N", default_value = "3", env = "TESSERACT_DOWNLOAD_RETRIES")]
+    pub download_retries: u32,
+
+    /// Read workspace metadata with the cargo on PATH (the default)
+    #[arg(long, conflicts_with_all = ["cargo_path", "use_rustup_cargo"], env = "TESSERACT_USE_SYSTEM_CARGO", value_parser = clap::builder::BoolishValueParser::new())]
+    pub use_system_cargo: bool,
+
+    /// Cargo binary used to read workspace metadata
+    #[arg(long, value_name = "PATH", conflicts_with = "use_rustup_cargo", env = "TESSERACT_CARGO_PATH")]
+    pub cargo_path: Option<PathBuf>,
+
+    /// Read workspace metadata with this rustup toolchain's cargo
+    #[arg(long, value_name = "TOOLCHAIN", env = "TESSERACT_USE_RUSTUP_CARGO")]
+    pub use_rustup_cargo: Option<String>,
+
+    /// With several servers, attempts on one server before moving to the next
+    #[arg(long, value_name = "N", default_value = "1", value_parser = clap::value_parser!(u32).range(1..), env = "TESSERACT_MAX_RETRIES_PER_SERVER")]
+    pub max_retries_per_server: u32,
+
+    /// Have the server compress full artifacts before sending them
+    #[arg(long, value_enum, value_name = "ALGORITHM", default_value = "none", env = "TESSERACT_COMPRESS_ARTIFACTS")]
+    pub compress_artifacts: CompressionAlgorithm,
+
+    /// Spinner animation; `line` is plain ASCII for terminals without Unicode support
+    #[arg(long, value_enum, value_name = "NAME", default_value = "braille", env = "TESSERACT_SPINNER_STYLE")]
+    pub spinner_style: SpinnerStyle,
+
+    /// Leave workspace files larger than this out of the tarball, with a warning (0 disables)
+    #[arg(long, value_name = "N", default_value = "1024", env = "TESSERACT_MAX_SOURCE_FILE_SIZE_KB")]
+    pub max_source_file_size_kb: u64,
+
+    /// Fail instead of skipping files over --max-source-file-size-kb
+    #[arg(long, env = "TESSERACT_STRICT_SIZE", value_parser = clap::builder::BoolishValueParser::new())]
+    pub strict_size: bool,
+
+    /// Accept any TLS certificate from the S3 endpoint used by --upload-artifacts-to-s3.
+    /// Only for local development! Build connections to the server don't use TLS.
+    #[arg(long, env = "TESSERACT_NO_VERIFY_TLS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub no_verify_tls: bool,
+
+    /// Local IP address to connect to the server from, on machines with several interfaces
+    #[arg(long, value_name = "ADDR", env = "TESSERACT_BIND_ADDRESS")]
+    pub bind_address: Option<std::net::IpAddr>,
+
+    /// Fail unless the build produced this artifact, given relative to the workspace or artifact directory (repeatable)
+    #[arg(long, value_name = "RELATIVE_PATH", env = "TESSERACT_REQUIRE_ARTIFACT", value_delimiter = ',')]
+    pub require_artifact: Vec<PathBuf>,
+
+    /// How to handle a dependency resolved at several versions: fail, list them, or say nothing
+    #[arg(long, value_enum, value_name = "POLICY", default_value = "ignore", env = "TESSERACT_PACKAGE_VERSION_MISMATCH")]
+    pub package_version_mismatch: VersionMismatchPolicy,
+
+    /// Hide a warning once it has been shown --dedup-threshold times
+    #[arg(long, overrides_with = "no_deduplicate_warnings", env = "TESSERACT_DEDUPLICATE_WARNINGS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub deduplicate_warnings: bool,
+
+    /// Show every warning, even with --deduplicate-warnings set in the environment
+    #[arg(long, overrides_with = "deduplicate_warnings")]
+    pub no_deduplicate_warnings: bool,
+
+    /// How many times --deduplicate-warnings shows the same warning
+    #[arg(long, value_name = "N", default_value = "3", value_parser = clap::value_parser!(u32).range(1..), env = "TESSERACT_DEDUP_THRESHOLD")]
+    pub dedup_threshold: u32,
+
+    /// Pass `-Z <FLAG>` to the server's cargo, e.g. minimal-versions (repeatable, needs a nightly server)
+    #[arg(long, value_name = "FLAG", env = "TESSERACT_CARGO_UNSTABLE_FLAGS", value_delimiter = ',')]
+    pub cargo_unstable_flags: Vec<String>,
+
+    /// In --watch mode, wait for a restarted server to come back instead of reporting a failed build
+    #[arg(long, env = "TESSERACT_RECONNECT_ON_SERVER_RESTART", value_parser = clap::builder::BoolishValueParser::new())]
+    pub reconnect_on_server_restart: bool,
+
+    /// How long --reconnect-on-server-restart waits before giving up
+    #[arg(long, value_name = "SECONDS", default_value = "300", env = "TESSERACT_WATCH_RECONNECT_TIMEOUT_SECS")]
+    pub watch_reconnect_timeout_secs: u64,
+
+    /// Read environment variables for the server's build from a .env file; --env wins on conflicts
+    #[arg(long, value_name = "DOTENV", env = "TESSERACT_INJECT_ENV_FROM_FILE")]
+    pub inject_env_from_file: Option<PathBuf>,
+
+    /// Strip debug info from downloaded binaries and shared libraries with strip or llvm-strip
+    #[arg(long, env = "TESSERACT_ARTIFACT_STRIP_DEBUG", value_parser = clap::builder::BoolishValueParser::new())]
+    pub artifact_strip_debug: bool,
+
+    /// With --artifact-strip-debug, keep the unstripped artifact as <path>.full
+    #[arg(long, requires = "artifact_strip_debug", env = "TESSERACT_KEEP_FULL_ARTIFACT", value_parser = clap::builder::BoolishValueParser::new())]
+    pub keep_full_artifact: bool,
+
+    /// Arguments for the binary started by --run
+    #[arg(last = true, value_name = "ARGS")]
+    pub exec_args: Vec<String>,
+}
+
+impl CliArgs {
+    /// Makes paths given relative to `cwd` absolute, for builds run from another directory
+    #[cfg(unix)]
+    fn resolve_paths(&mut self, cwd: &Path) {
+        let resolve = |path: &mut PathBuf| *path = cwd.join(&*path);
+        self.log_file.iter_mut().for_each(resolve);
+        self.externs.iter_mut().for_each(|(_, path)| resolve(path));
+        self.extra_include_dir.iter_mut().for_each(resolve);
+        self.source_archive.iter_mut().for_each(resolve);
+        self.checksum_file.iter_mut().for_each(resolve);
+        self.inject_env_from_file.iter_mut().for_each(resolve);
+        // A bare program name is looked up on PATH
+        self.cargo_path.iter_mut().filter(|path| path.components().count() > 1).for_each(resolve);
+    }
+
+    /// Checks values clap can't, reporting every problem at once
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = Vec::new();
+
+        for server in &self.server {
+            let valid = server
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok_and(|port| port > 0));
+            if !valid {
+                errors.push(format!("Invalid server address '{}': expected host:port format", server));
+            }
+        }
+
+        if self.retries == 0 {
+            errors.push("--retries must be greater than 0".to_string());
+        }
+
+        if let Some(ref path) = self.inject_env_from_file {
+            if let Err(e) = std::fs::File::open(path) {
+                errors.push(format!("Cannot read --inject-env-from-file {}: {}", path.display(), e));
+            }
+        }
+
+        for flag in &self.cargo_unstable_flags {
+            if flag.is_empty() || flag.starts_with('-') {
+                errors.push(format!("Invalid --cargo-unstable-flags '{}': give the flag without -Z, e.g. build-std=core", flag));
+            }
+        }
+
+        if let Some(ref target) = self.target {
+            static TRIPLE: OnceLock<regex::Regex> = OnceLock::new();
+            let triple = TRIPLE.get_or_init(|| regex::Regex::new(r"^[a-z0-9_]+(-[a-zA-Z0-9_.]+){1,3}$").unwrap());
+            // Custom targets are given as a path to a target spec file
+            if !triple.is_match(target) && !target.ends_with(".json") {
+                errors.push(format!("Invalid target '{}': expected a target triple such as x86_64-unknown-linux-gnu", target));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(errors.join("\n")))
+        }
+    }
+}
+
+/// Which of the server's environment variables reach the `cargo build` subprocess
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EnvFilter {
+    AllowAll,
+    DenyAll,
+    AllowList(Vec<String>),
+    DenyList(Vec<String>),
+}
+
+impl EnvFilter {
+    fn parse(s: &str) -> Result<Self, String> {
+        let vars = |list: &str| -> Vec<String> {
+            list.split(',').map(str::trim).filter(|var| !var.is_empty()).map(str::to_string).collect()
+        };
+        match s.split_once(':') {
+            None if s == "allow-all" => Ok(EnvFilter::AllowAll),
+            None if s == "deny-all" => Ok(EnvFilter::DenyAll),
+            Some(("allow", list)) => Ok(EnvFilter::AllowList(vars(list))),
+            Some(("deny", list)) => Ok(EnvFilter::DenyList(vars(list))),
+            _ => Err(format!(
+                "expected allow-all, deny-all, allow:VAR,... or deny:VAR,..., got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn from_name(name: &str) -> Option<Self> {
+        LogLevel::from_str(name.trim(), true).ok()
+    }
+}
+
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Incremental {
+    True,
+    False,
+    /// Leave CARGO_INCREMENTAL to the server
+    Auto,
+}
+
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum VersionMismatchPolicy {
+    Strict,
+    Warn,
+    Ignore,
+}
+
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SplitDebuginfo {
+    Off,
+    Packed,
+    Unpacked,
+}
+
+impl SplitDebuginfo {
+    fn as_str(self) -> &'static str {
+        match self {
+            SplitDebuginfo::Off => "off",
+            SplitDebuginfo::Packed => "packed",
+            SplitDebuginfo::Unpacked => "unpacked",
+        }
+    }
+}
+
+/// A tool the server should have, as checked by `cargo tess verify-install`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolSpec {
+    pub name: String,
+    pub min_version: Option<cargo_metadata::semver::Version>,
+}
+
+impl ToolSpec {
+    fn parse(value: &str) -> Result<Self, String> {
+        let (name, min_version) = match value.split_once('@') {
+            Some((name, version)) => (
+                name,
+                Some(cargo_metadata::semver::Version::parse(version).map_err(|e| format!("invalid version '{}': {}", version, e))?),
+            ),
+            None => (value, None),
+        };
+        if name.is_empty() {
+            return Err(format!("expected TOOL or TOOL@VERSION, got '{}'", value));
+        }
+        Ok(Self { name: name.to_string(), min_version })
+    }
+}
+
+impl std::fmt::Display for ToolSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.min_version {
+            Some(ref version) => write!(f, "{} >= {}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Chain-of-custody record written next to an artifact by `--trace-artifact-provenance`
+#[derive(Debug, Serialize)]
+pub struct ProvenanceRecord {
+    pub server: String,
+    pub built_at: String,
+    pub source_sha256: String,
+    pub toolchain: String,
+    pub rustflags: Vec<String>,
+    pub features: Vec<String>,
+    pub commit: Option<String>,
+}
+
+/// Bucket and key prefix parsed from an `s3://bucket/prefix` URL
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct S3Destination {
+    pub bucket: String,
+    pub prefix: String,
+}
+
+impl S3Destination {
+    fn parse(s: &str) -> Result<Self, String> {
+        let rest = s
+            .strip_prefix("s3://")
+            .ok_or_else(|| format!("expected s3://bucket/prefix, got '{}'", s))?;
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(format!("missing bucket name in '{}'", s));
+        }
+        Ok(Self {
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+        })
+    }
+
+    fn key(&self, relative: &Path) -> String {
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        if self.prefix.is_empty() {
+            relative
+        } else {
+            format!("{}/{}", self.prefix, relative)
+        }
+    }
+}
+
+fn parse_extern(value: &str) -> Result<(String, PathBuf), String> {
+    let (name, path) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=PATH, got '{}'", value))?;
+    let path = PathBuf::from(path);
+    if !path.is_file() {
+        return Err(format!("'{}' is not a file", path.display()));
+    }
+    Ok((name.to_string(), path))
+}
+
+fn parse_remap_path_prefix(value: &str) -> Result<String, String> {
+    match value.split_once('=') {
+        Some((from, _)) if !from.is_empty() => Ok(value.to_string()),
+        _ => Err(format!("expected FROM=TO, got '{}'", value)),
+    }
+}
+
+fn parse_key_value(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", value)),
+    }
+}
+
+fn parse_target_feature(value: &str) -> Result<String, String> {
+    // Dots appear in real feature names such as sse4.1
+    match value.strip_prefix(['+', '-']) {
+        Some(name) if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') => {
+            Ok(value.to_string())
+        }
+        _ => Err(format!("expected +FEATURE or -FEATURE, got '{}'", value)),
+    }
+}
+
+/// Parses a duration such as `90s`, `30m`, `24h` or `7d` into seconds
+fn parse_period(value: &str) -> Result<u64, String> {
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| format!("expected a period like 24h or 7d, got '{}'", value))?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" | "" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("unknown period unit '{}'; use s, m, h, d or w", unit)),
+    };
+    Ok(amount * unit_secs)
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Store an auth token for a server in the OS keychain
+    Login {
+        /// Server address (host:port)
+        #[arg(short, long)]
+        server: String,
+    },
+    /// Remove the stored auth token for a server
+    Logout {
+        /// Server address (host:port)
+        #[arg(short, long)]
+        server: String,
+    },
+    /// Print a systemd unit and Nginx TLS proxy config for a tesseract-server host
+    InitServer {
+        /// Port the server listens on
+        #[arg(short, long, default_value = "9876")]
+        port: u16,
+        /// Directory for server logs
+        #[arg(short, long, default_value = "/var/log/tesseract")]
+        log_dir: PathBuf,
+        /// User the server runs as
+        #[arg(short, long, default_value = "tesseract")]
+        user: String,
+    },
+    /// Query the server's Rust toolchain (like `rustc --print`)
+    Meta {
+        #[arg(value_enum)]
+        query: MetaQuery,
+    },
+    /// Build and run the workspace tests on the server
+    Test {
+        /// Only run tests whose names contain this pattern
+        #[arg(long, value_name = "PATTERN")]
+        test_filter: Option<String>,
+        /// Only run tests marked #[ignore]
+        #[arg(long)]
+        test_ignored: bool,
+        /// Show the tests' stdout and stderr instead of capturing it
+        #[arg(long)]
+        test_nocapture: bool,
+    },
+    /// Show the server's build environment variables
+    Env {
+        /// Include variables that look like secrets (TOKEN, SECRET, KEY, PASSWORD)
+        #[arg(long)]
+        show_sensitive: bool,
+    },
+    /// List the compilation targets available on the server
+    ListTargets,
+    /// Update the server's Rust toolchain and components
+    Update {
+        /// Toolchain to update
+        #[arg(default_value = "stable")]
+        toolchain: String,
+        /// Component to install alongside the toolchain (repeatable)
+        #[arg(short, long = "component")]
+        components: Vec<String>,
+    },
+    /// Show build metrics the server recorded over a recent period
+    ServerStats {
+        /// How far back to look, e.g. 30m, 24h or 7d
+        #[arg(default_value = "24h", value_parser = parse_period)]
+        period: u64,
+    },
+    /// Show the server's log
+    ServerLog {
+        /// Keep streaming new lines, like `tail -f`
+        #[arg(short, long)]
+        follow: bool,
+        /// Number of past lines to show
+        #[arg(short = 'n', long, default_value = "100")]
+        lines: u32,
+        /// Only show lines at this level or more severe
+        #[arg(long, value_enum, value_name = "LEVEL")]
+        filter: Option<LogLevel>,
+    },
+    /// Run `cargo publish` on the server
+    Publish {
+        /// Package to publish (required in multi-package workspaces)
+        #[arg(short, long)]
+        package: Option<String>,
+        /// Package and verify without uploading
+        #[arg(long)]
+        dry_run: bool,
+        /// Registry to publish to
+        #[arg(long)]
+        registry: Option<String>,
+        /// Registry API token
+        #[arg(long, env = "CARGO_REGISTRY_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+    },
+    /// Run `cargo vendor` and upload the dependencies so the server can build offline
+    Vendor,
+    /// Check artifacts on disk against a manifest written by --checksum-file
+    Verify {
+        #[arg(long, value_name = "PATH")]
+        checksum_file: PathBuf,
+    },
+    /// Compare two cached builds of an artifact with diffoscope (or objdump and diff)
+    Diff {
+        /// SHA-256 prefix of the older artifact
+        before: String,
+        /// SHA-256 prefix of the newer artifact
+        after: String,
+        /// Artifact file name, e.g. my-app
+        artifact: String,
+    },
+    /// Check the configuration and workspace for common problems
+    Diagnose,
+    /// Check which build tools are installed on the server
+    VerifyInstall {
+        /// Fail unless this tool is installed, optionally at a minimum version (repeatable)
+        #[arg(long, value_name = "TOOL[@VERSION]", value_parser = ToolSpec::parse)]
+        require: Vec<ToolSpec>,
+    },
+    /// Start or stop a background daemon that serves builds for this workspace, keeping
+    /// its metadata cached between them
+    Daemon {
+        #[command(subcommand)]
+        action: Option<DaemonAction>,
+    },
+    /// Print a shell completion script to stdout
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, Copy)]
+pub enum DaemonAction {
+    /// Start the daemon in the background (the default)
+    Start,
+    /// Shut the running daemon down
+    Stop,
+    /// Report whether a daemon is running
+    Status,
+    /// Run the daemon in the foreground
+    #[command(hide = true)]
+    Serve,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum CargoCommand {
+    Build,
+    Test,
+}
+
+impl CargoCommand {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CargoCommand::Build => "build",
+            CargoCommand::Test => "test",
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum MetaQuery {
+    Sysroot,
+    TargetLibdir,
+    Host,
+    Target,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BuildUnit {
+    pub package_name: String,
+    pub version: String,
+    /// Features declared in the package's Cargo.toml
+    pub declared_features: Vec<String>,
+    pub dependencies: Vec<String>,
+    /// Dependencies compiled for the host: build-dependencies and proc-macro crates
+    pub proc_macro_deps: Vec<String>,
+    /// Built for the host even when `target` is set
+    pub is_proc_macro: bool,
+    pub source_files: Vec<PathBuf>,
+    pub artifacts: Vec<PathBuf>,
+}
+
+/// Kind of a Cargo target, as reported by `cargo metadata`
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TargetKind {
+    Lib,
+    Bin,
+    Cdylib,
+    Staticlib,
+    ProcMacro,
+    Test,
+    Example,
+    Bench,
+}
+
+impl TargetKind {
+    fn matches(self, kind: &str) -> bool {
+        kind == match self {
+            TargetKind::Lib => "lib",
+            TargetKind::Bin => "bin",
+            TargetKind::Cdylib => "cdylib",
+            TargetKind::Staticlib => "staticlib",
+            TargetKind::ProcMacro => "proc-macro",
+            TargetKind::Test => "test",
+            TargetKind::Example => "example",
+            TargetKind::Bench => "bench",
+        }
+    }
+}
+
+/// Selects which packages and targets `discover_build_units_filtered` returns.
+/// An empty list accepts everything.
+#[derive(Debug, Clone, Default)]
+pub struct UnitFilter {
+    pub kinds: Vec<TargetKind>,
+    pub packages: Vec<String>,
+    pub bins: Vec<String>,
+    /// Targets with any of these kinds are dropped, even if `kinds` selects them
+    pub ignored_kinds: Vec<TargetKind>,
+}
+
+impl UnitFilter {
+    fn accepts_package(&self, name: &str) -> bool {
+        self.packages.is_empty() || self.packages.iter().any(|p| p == name)
+    }
+
+    fn accepts_target(&self, target: &cargo_metadata::Target, default_kinds: &[TargetKind]) -> bool {
+        let kinds = if self.kinds.is_empty() { default_kinds } else { &self.kinds };
+        let kind_matches = kinds.iter().any(|kind| target.kind.iter().any(|k| kind.matches(k)))
+            && !self.ignored_kinds.iter().any(|kind| target.kind.iter().any(|k| kind.matches(k)));
+        let is_bin = target.kind.iter().any(|k| k == "bin");
+        kind_matches && (!is_bin || self.bins.is_empty() || self.bins.contains(&target.name))
+    }
+}
+
+/// Last seen state of a source file, kept in target/tesseract/file-mtimes/<package>.json so unchanged
+/// files don't have to be re-read to detect changes
+#[derive(Serialize, Deserialize)]
+struct FileFingerprint {
+    path: String,
+    mtime_secs: i64,
+    mtime_nsecs: u32,
+    size: u64,
+    sha256: String,
+}
+
+/// A compressed tarball kept in target/tesseract/last-upload for `--no-tarball-rebuild`
+#[derive(Serialize, Deserialize)]
+struct CachedTarball {
+    source_sha256: [u8; 32],
+    compression: TarballCompression,
+    data: Vec<u8>,
+}
+
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SpinnerStyle {
+    Braille,
+    Dots,
+    Line,
+    Star,
+    Arrow,
+}
+
+impl SpinnerStyle {
+    /// Animation frames followed by the frame shown once finished
+    fn tick_chars(self) -> &'static str {
+        match self {
+            SpinnerStyle::Braille => "⠁⠂⠄⡀⢀⠠⠐⠈ ",
+            SpinnerStyle::Dots => "⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ",
+            SpinnerStyle::Line => "-\\|/ ",
+            SpinnerStyle::Star => "✶✸✹✺✹✷ ",
+            SpinnerStyle::Arrow => "←↖↑↗→↘↓↙ ",
+        }
+    }
+}
+
+/// How progress is drawn in the terminal
+#[derive(Debug, Clone, Copy)]
+struct DisplayConfig {
+    spinner: SpinnerStyle,
+}
+
+/// Compression the server applies to `ArtifactData::Full` and `ArtifactData::Chunked` artifacts
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    Gzip,
+    /// zstd at level 3
+    Zstd,
+    None,
+}
+
+impl CompressionAlgorithm {
+    fn decompress(self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            CompressionAlgorithm::Gzip => {
+                let mut decompressed = Vec::new();
+                flate2::read::GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?;
+                Ok(decompressed)
+            }
+            CompressionAlgorithm::Zstd => Ok(zstd::stream::decode_all(data.as_slice())?),
+            CompressionAlgorithm::None => Ok(data),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TarballCompression {
+    Gzip,
+    /// The dictionary itself is only sent when the server asks for it with `UnknownDictionary`
+    Zstd {
+        dictionary_id: u32,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
+pub enum BuildRequest {
+    BuildUnit {
+        build_id: Uuid,
+        unit: BuildUnit,
+        command: CargoCommand,
+        release: bool,
+        target: Option<String>,
+        jobs: Option<u32>,
+        timings: bool,
+        auth_token: Option<String>,
+        rustflags: Vec<String>,
+        extern_crates: HashMap<String, PathBuf>,
+        existing_artifact_hashes: HashMap<PathBuf, [u8; 32]>,
+        hash_algorithm: HashAlgorithm,
+        /// `--cap-lints` for dependencies; `None` leaves the server default
+        cap_lints: Option<String>,
+        features: Vec<String>,
+        /// Client-side paths of the directories packed under extra/<basename>
+        extra_include_dirs: Vec<PathBuf>,
+        build_std: Vec<String>,
+        /// Each passed to cargo as `-Z <flag>`; a stable server answers `ToolchainFeatureUnavailable`
+        cargo_z_flags: Vec<String>,
+        /// Return `.d` files with paths remapped onto `client_workspace_root`
+        emit_dep_info: bool,
+        /// Add `--remap-path-prefix=<server build dir>=<client_workspace_root>` to RUSTFLAGS
+        auto_remap: bool,
+        client_workspace_root: PathBuf,
+        /// `--check-cfg` specs; kept out of `rustflags` so servers older than Rust 1.80 can drop them
+        check_cfg: Vec<String>,
+        /// Kill the build with `BuildErrorKind::OutOfMemory` past this much resident memory
+        memory_limit_mb: Option<u64>,
+        /// Passed to the server's cargo as `--target-dir`
+        target_dir_server: Option<String>,
+        /// `CARGO_INCREMENTAL` on the server; `None` leaves the server default
+        incremental: Option<bool>,
+        /// Run cargo-udeps / cargo-machete after the build and report `UnusedDeps`
+        check_unused_deps: bool,
+        env_filter: EnvFilter,
+        use_sparse_registry: bool,
+        /// SHA-256 over the workspace's Cargo.toml files, sorted by path relative to the tarball root
+        metadata_checksum: [u8; 32],
+        /// Build offline from the vendor archive uploaded for this Cargo.lock
+        vendored_lockfile: Option<[u8; 32]>,
+        /// crates.io dependencies to patch to an exact version, from the local Cargo.lock
+        pinned_deps: HashMap<String, String>,
+        /// Extra environment variables for the server's cargo
+        env: HashMap<String, String>,
+        /// Passed to `cargo rustc --crate-type` in place of the manifest's crate type
+        crate_type_override: Option<String>,
+        keep_workspace_on_error: bool,
+        /// Send artifacts as `ArtifactChunk`s that can be resumed with `ResumeArtifact`
+        chunked_artifacts: bool,
+        compress_artifacts: CompressionAlgorithm,
+        /// Test harness arguments, only used for `CargoCommand::Test`
+        test_filter: Option<String>,
+        test_ignored: bool,
+        test_nocapture: bool,
+        /// Discard any workspace state the server cached for this client
+        refresh_metadata: bool,
+        tarball_compression: TarballCompression,
+        /// Dictionary for `TarballCompression::Zstd`, sent after an `UnknownDictionary` response
+        zstd_dictionary: Option<Vec<u8>>,
+        tarball_data: Vec<u8>,
+    },
+    TransferArtifact {
+        from_unit: String,
+        artifact_path: PathBuf,
+    },
+    Heartbeat {
+        auth_token: Option<String>,
+    },
+    Handshake {
+        client_version: String,
+    },
+    PrintMeta {
+        query: String,
+    },
+    CaptureEnv,
+    /// Resume streaming an in-progress build after the connection dropped
+    ReconnectBuild {
+        build_id: Uuid,
+    },
+    ListTargets,
+    /// Continue sending a finished build's artifacts from the given byte offsets
+    ResumeArtifact {
+        build_id: Uuid,
+        offsets: HashMap<PathBuf, u64>,
+    },
+    /// Run `rustup update` and `rustup component add` on the server
+    UpdateToolchain {
+        toolchain: String,
+        components: Vec<String>,
+        auth_token: Option<String>,
+    },
+    /// Stream the last `lines` of the server log, then new lines too if `follow`
+    TailLog {
+        lines: u32,
+        follow: bool,
+        auth_token: Option<String>,
+    },
+    /// Run `cargo publish` for `unit` from the uploaded sources
+    Publish {
+        unit: BuildUnit,
+        dry_run: bool,
+        registry: Option<String>,
+        token: Option<String>,
+        auth_token: Option<String>,
+        tarball_compression: TarballCompression,
+        zstd_dictionary: Option<Vec<u8>>,
+        tarball_data: Vec<u8>,
+    },
+    GetStats {
+        period_secs: u64,
+        auth_token: Option<String>,
+    },
+    /// Run each tool with `--version` and report which are missing or too old
+    VerifyInstall {
+        required_tools: Vec<ToolSpec>,
+        auth_token: Option<String>,
+    },
+    /// Gzipped `vendor/` directory and the `.cargo/config.toml` that points cargo at it
+    VendorArchive {
+        lockfile_sha256: [u8; 32],
+        tarball: Vec<u8>,
+        auth_token: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum BuildErrorKind {
+    CompileError,
+    LinkerError,
+    OutOfMemory,
+    DiskFull,
+    InternalServerError,
+    Timeout,
+}
+
+impl BuildErrorKind {
+    /// Classify a failed cargo invocation from its exit code and output
+    pub fn classify(exit_code: Option<i32>, output: &str) -> Self {
+        if output.contains("No space left on device") {
+            BuildErrorKind::DiskFull
+        } else if exit_code == Some(137)
+            || output.contains("memory allocation of")
+            || output.contains("out of memory")
+        {
+            BuildErrorKind::OutOfMemory
+        } else if output.contains("linking with")
+            || output.contains("ld returned 1 exit status")
+            || output.contains("undefined reference to")
+        {
+            BuildErrorKind::LinkerError
+        } else if output.contains("error[E") || output.contains("error: could not compile") {
+            BuildErrorKind::CompileError
+        } else if exit_code.is_none() {
+            BuildErrorKind::Timeout
+        } else {
+            BuildErrorKind::InternalServerError
+        }
+    }
+
+    pub fn hint(&self) -> &'static str {
+        match self {
+            BuildErrorKind::CompileError => "run `cargo check` locally to see the errors faster",
+            BuildErrorKind::LinkerError => {
+                "check that the system libraries and linker for the target are installed on the server"
+            }
+            BuildErrorKind::OutOfMemory => "the server ran out of memory; try --server-jobs with a lower value",
+            BuildErrorKind::DiskFull => "the server ran out of disk space; contact the server administrator",
+            BuildErrorKind::InternalServerError => "this looks like a server problem; check the server logs",
+            BuildErrorKind::Timeout => "the build timed out on the server",
+        }
+    }
+}
+
+impl std::fmt::Display for BuildErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            BuildErrorKind::CompileError => "compile error",
+            BuildErrorKind::LinkerError => "linker error",
+            BuildErrorKind::OutOfMemory => "out of memory",
+            BuildErrorKind::DiskFull => "disk full",
+            BuildErrorKind::InternalServerError => "internal server error",
+            BuildErrorKind::Timeout => "timeout",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ArtifactData {
+    /// Matches the hash the client sent in `existing_artifact_hashes`
+    Unchanged,
+    /// Binary delta against the client's existing artifact
+    Delta(Vec<u8>),
+    Full(Vec<u8>),
+    /// Sent beforehand as `BuildResponse::ArtifactChunk`s
+    Chunked,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArtifactEntry {
+    pub path: PathBuf,
+    pub data: ArtifactData,
+    /// Hash of the complete artifact, computed with the request's `hash_algorithm`
+    pub hash: Vec<u8>,
+}
+
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn hash(&self, data: &[u8]) -> Vec<u8> {
+        fn digest<D: Digest>(data: &[u8]) -> Vec<u8> {
+            D::digest(data).to_vec()
+        }
+
+        match self {
+            HashAlgorithm::Sha256 => digest::<Sha256>(data),
+            HashAlgorithm::Sha512 => digest::<Sha512>(data),
+            HashAlgorithm::Blake3 => digest::<blake3::Hasher>(data),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BuildResponse {
+    /// Echoes the `build_id` of a `BuildRequest::BuildUnit`; the build can be resumed with `ReconnectBuild`
+    BuildAccepted {
+        build_id: Uuid,
+    },
+    BuildOutput {
+        unit_name: String,
+        output: String,
+        is_error: bool,
+    },
+    BuildComplete {
+        unit_name: String,
+        artifacts: Vec<ArtifactEntry>,
+        /// `rustc --version` of the toolchain that built the unit
+        toolchain: String,
+    },
+    BuildError {
+        unit_name: String,
+        error: String,
+        error_kind: BuildErrorKind,
+        /// Server-side workspace left in place because of `keep_workspace_on_error`
+        workspace_path: Option<String>,
+    },
+    TimingReport {
+        unit_name: String,
+        html: String,
+    },
+    HeartbeatAck,
+    ServerVersion {
+        version: String,
+    },
+    MetaResult {
+        value: String,
+    },
+    ServerEnv {
+        vars: HashMap<String, String>,
+    },
+    AvailableTargets {
+        installed: Vec<String>,
+        available: Vec<String>,
+    },
+    /// Sub-unit progress, derived from cargo's JSON messages on the server
+    Progress {
+        unit_name: String,
+        stage: BuildStage,
+        message: String,
+        percent: Option<u8>,
+    },
+    UpdateComplete {
+        old_version: String,
+        new_version: String,
+    },
+    /// Part of an artifact sent with `ArtifactData::Chunked`, sent before `BuildComplete`
+    ArtifactChunk {
+        path: PathBuf,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Sent before `BuildComplete`: unused dependencies per package
+    UnusedDeps {
+        packages: Vec<(String, Vec<String>)>,
+    },
+    LogLine {
+        /// Unix time in seconds
+        timestamp: u64,
+        level: String,
+        message: String,
+    },
+    PublishComplete {
+        package_name: String,
+        version: String,
+    },
+    Stats {
+        total_builds: u64,
+        successful: u64,
+        failed: u64,
+        avg_build_secs: f64,
+        p95_build_secs: f64,
+        cache_hit_rate: f32,
+        total_data_transferred_bytes: u64,
+    },
+    /// The server doesn't record build metrics
+    StatsUnavailable,
+    ToolsStatus {
+        available: Vec<ToolSpec>,
+        missing: Vec<ToolSpec>,
+    },
+    /// The vendor archive was extracted to `vendor_dir` on the server
+    VendorStored {
+        vendor_dir: String,
+    },
+    /// The server is busy; send the build again after `retry_after_secs`
+    RateLimited {
+        retry_after_secs: u32,
+    },
+    /// The uploaded manifests don't match the request's `metadata_checksum`
+    MetadataChecksumMismatch {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    /// The server has no zstd dictionary with this ID; send the build again with `zstd_dictionary`
+    UnknownDictionary {
+        dictionary_id: u32,
+    },
+    /// The server's cargo is too old for a requested feature; the build continues without it
+    FeatureUnavailable {
+        feature: String,
+        min_cargo_version: String,
+    },
+    /// The build's resident memory is approaching the server's threshold
+    MemoryWarning {
+        used_bytes: u64,
+        limit_bytes: u64,
+    },
+    /// The server's toolchain can't provide a requested feature (e.g. `-Z build-std` on stable)
+    ToolchainFeatureUnavailable {
+        unit_name: String,
+        feature: String,
+        reason: String,
+    },
+    /// The server ran out of disk space (ENOSPC) extracting the tarball or building
+    DiskQuotaExceeded {
+        available_bytes: u64,
+        required_bytes: u64,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStage {
+    Downloading,
+    Compiling,
+    Linking,
+    Running,
+}
+
+impl std::fmt::Display for BuildStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BuildStage::Downloading => "Downloading",
+            BuildStage::Compiling => "Compiling",
+            BuildStage::Linking => "Linking",
+            BuildStage::Running => "Running",
+        })
+    }
+}
+
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Write artifacts with the names the server sent
+    Server,
+    /// Rename artifacts to Cargo's naming conventions for the target platform
+    Cargo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArtifactOs {
+    Windows,
+    MacOs,
+    Unix,
+}
+
+impl ArtifactOs {
+    fn cargo_file_name(self, path: &Path) -> PathBuf {
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            return path.to_path_buf();
+        };
+        let (stem, extension) = match file_name.rsplit_once('.') {
+            Some((stem, extension)) => (stem, Some(extension)),
+            None => (file_name, None),
+        };
+        let lib_name = stem.strip_prefix("lib").unwrap_or(stem);
+
+        let renamed = match (extension, self) {
+            (None | Some("exe"), ArtifactOs::Windows) => format!("{}.exe", stem),
+            (None | Some("exe"), _) => stem.to_string(),
+            (Some("so" | "dylib" | "dll"), ArtifactOs::Windows) => format!("{}.dll", lib_name),
+            (Some("so" | "dylib" | "dll"), ArtifactOs::MacOs) => format!("lib{}.dylib", lib_name),
+            (Some("so" | "dylib" | "dll"), ArtifactOs::Unix) => format!("lib{}.so", lib_name),
+            (Some("a" | "lib"), ArtifactOs::Windows) => format!("{}.lib", lib_name),
+            (Some("a" | "lib"), _) => format!("lib{}.a", lib_name),
+            _ => file_name.to_string(),
+        };
+        path.with_file_name(renamed)
+    }
+}
+
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// Full rustc diagnostics with source snippets
+    Human,
+    /// One line per diagnostic: `file:line:col: level: message`
+    Short,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RustcLine {
+    /// `error[E0425]: message` or `warning: message`
+    Header(String),
+    /// ` --> src/main.rs:2:5`
+    Location(String),
+    /// Source snippets, notes and help attached to a diagnostic
+    Annotation,
+    Other,
+}
+
+pub fn parse_rustc_output_line(line: &str) -> RustcLine {
+    static HEADER: OnceLock<regex::Regex> = OnceLock::new();
+    static ANNOTATION: OnceLock<regex::Regex> = OnceLock::new();
+    let header = HEADER.get_or_init(|| {
+        regex::Regex::new(r"^(error|warning)(\[[A-Za-z0-9_]+\])?: .+").unwrap()
+    });
+    let annotation = ANNOTATION.get_or_init(|| {
+        regex::Regex::new(r"^\s*(\d+\s*)?(\||=|\.\.\.|:::)|^\s*$").unwrap()
+    });
+
+    if header.is_match(line) && !line.contains(" generated ") {
+        RustcLine::Header(line.to_string())
+    } else if let Some(location) = line.trim_start().strip_prefix("--> ") {
+        RustcLine::Location(location.trim().to_string())
+    } else if annotation.is_match(line) {
+        RustcLine::Annotation
+    } else {
+        RustcLine::Other
+    }
+}
+
+/// Collapses multi-line rustc diagnostics into `--error-format short` lines
+#[derive(Default)]
+struct ShortDiagnostics {
+    pending: Option<String>,
+    in_diagnostic: bool,
+}
+
+impl ShortDiagnostics {
+    fn format_line(&mut self, line: &str) -> Vec<String> {
+        match parse_rustc_output_line(line) {
+            RustcLine::Header(header) => {
+                self.in_diagnostic = true;
+                self.pending.replace(header).into_iter().collect()
+            }
+            RustcLine::Location(location) => match self.pending.take() {
+                Some(header) => vec![format!("{}: {}", location, header)],
+                None => Vec::new(),
+            },
+            RustcLine::Annotation if self.in_diagnostic => Vec::new(),
+            _ => {
+                self.in_diagnostic = false;
+                self.pending.take().into_iter().chain([line.to_string()]).collect()
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Option<String> {
+        self.in_diagnostic = false;
+        self.pending.take()
+    }
+}
+
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ColorScheme {
+    /// Plain terminal output
+    Default,
+    /// GitHub Actions `::error` annotations and log groups
+    Github,
+    /// GitLab CI collapsible sections
+    Gitlab,
+}
+
+impl ColorScheme {
+    pub fn detect(env_var: impl Fn(&str) -> Option<String>) -> Self {
+        let is_set = |var: &str| env_var(var).is_some_and(|value| value == "true");
+        if is_set("GITHUB_ACTIONS") {
+            ColorScheme::Github
+        } else if is_set("GITLAB_CI") {
+            ColorScheme::Gitlab
+        } else {
+            ColorScheme::Default
+        }
+    }
+
+    pub fn formatter(self) -> Box<dyn CiFormatter> {
+        match self {
+            ColorScheme::Default => Box::new(DefaultFormatter),
+            ColorScheme::Github => Box::new(GithubFormatter::default()),
+            ColorScheme::Gitlab => Box::new(GitlabFormatter),
+        }
+    }
+}
+
+fn color_output(line: &str, is_error: bool) -> String {
+    if is_error {
+        line.red().to_string()
+    } else {
+        line.green().to_string()
+    }
+}
+
+/// Adapts build output to the log conventions of a CI system
+pub trait CiFormatter: Send {
+    fn format_output(&mut self, line: &str, is_error: bool) -> String;
+
+    /// Line opening a collapsible block of output for `name`
+    fn section_start(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    fn section_end(&self, _name: &str) -> Option<String> {
+        None
+    }
+}
+
+pub struct DefaultFormatter;
+
+impl CiFormatter for DefaultFormatter {
+    fn format_output(&mut self, line: &str, is_error: bool) -> String {
+        color_output(line, is_error)
+    }
+}
+
+/// Emits `::error file=..,line=..::` workflow commands so diagnostics show up on the PR diff
+#[derive(Default)]
+pub struct GithubFormatter {
+    pending_header: Option<String>,
+}
+
+impl GithubFormatter {
+    fn annotation(header: &str, location: &str) -> Option<String> {
+        let (level, message) = header.split_once(": ")?;
+        let level = if level.starts_with("error") { "error" } else { "warning" };
+        let mut parts = location.rsplitn(3, ':');
+        let (col, line, file) = (parts.next()?, parts.next()?, parts.next()?);
+        Some(format!("::{} file={},line={},col={}::{}", level, file, line, col, message))
+    }
+}
+
+impl CiFormatter for GithubFormatter {
+    fn format_output(&mut self, line: &str, is_error: bool) -> String {
+        static SHORT: OnceLock<regex::Regex> = OnceLock::new();
+        let short = SHORT.get_or_init(|| {
+            regex::Regex::new(r"^(\S+:\d+:\d+): ((error|warning).*)$").unwrap()
+        });
+
+        let annotation = match parse_rustc_output_line(line) {
+            RustcLine::Header(header) => {
+                self.pending_header = Some(header);
+                None
+            }
+            RustcLine::Location(location) => self
+                .pending_header
+                .take()
+                .and_then(|header| Self::annotation(&header, &location)),
+            _ => short
+                .captures(line)
+                .and_then(|captures| Self::annotation(&captures[2], &captures[1])),
+        };
+        // Workflow commands must start the line, so they are never colored
+        match annotation {
+            Some(annotation) => format!("{}\n{}", annotation, color_output(line, is_error)),
+            None => color_output(line, is_error),
+        }
+    }
+
+    fn section_start(&self, name: &str) -> Option<String> {
+        Some(format!("::group::{}", name))
+    }
+
+    fn section_end(&self, _name: &str) -> Option<String> {
+        Some("::endgroup::".to_string())
+    }
+}
+
+/// Wraps each package's output in a collapsible `section_start`/`section_end` block
+pub struct GitlabFormatter;
+
+impl GitlabFormatter {
+    fn section_id(name: &str) -> String {
+        let name: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c.to_ascii_lowercase() } else { '_' })
+            .collect();
+        format!("tesseract_{}", name)
+    }
+
+    fn timestamp() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+}
+
+impl CiFormatter for GitlabFormatter {
+    fn format_output(&mut self, line: &str, is_error: bool) -> String {
+        color_output(line, is_error)
+    }
+
+    fn section_start(&self, name: &str) -> Option<String> {
+        Some(format!(
+            "\x1b[0Ksection_start:{}:{}[collapsed=true]\r\x1b[0K{}",
+            Self::timestamp(),
+            Self::section_id(name),
+            name
+        ))
+    }
+
+    fn section_end(&self, name: &str) -> Option<String> {
+        Some(format!("\x1b[0Ksection_end:{}:{}\r\x1b[0K", Self::timestamp(), Self::section_id(name)))
+    }
+}
+
+/// An `ssh -L` port forward to the build server; the ssh process is killed on drop
+pub struct SshTunnel {
+    child: std::process::Child,
+    local_addr: String,
+}
+
+impl SshTunnel {
+    pub async fn open(tunnel_host: &str, server: &str) -> Result<Self> {
+        let local_port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let local_addr = format!("127.0.0.1:{}", local_port);
+
+        info!("Opening SSH tunnel {} -> {} via {}", local_addr, server, tunnel_host);
+        let child = std::process::Command::new("ssh")
+            .args(["-N", "-o", "ExitOnForwardFailure=yes", "-L"])
+            .arg(format!("{}:{}", local_port, server))
+            .arg(tunnel_host)
+            .stdin(std::process::Stdio::null())
+            .spawn()
+            .context("Failed to start ssh")?;
+        let mut tunnel = Self { child, local_addr };
+
+        for _ in 0..SSH_TUNNEL_POLL_ATTEMPTS {
+            if let Some(status) = tunnel.child.try_wait()? {
+                return Err(anyhow::anyhow!("ssh exited before the tunnel was ready ({})", status));
+            }
+            if TcpStream::connect(&tunnel.local_addr).await.is_ok() {
+                return Ok(tunnel);
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+        Err(anyhow::anyhow!("Timed out waiting for SSH tunnel via {}", tunnel_host))
+    }
+
+    pub fn local_addr(&self) -> &str {
+        &self.local_addr
+    }
+}
+
+impl Drop for SshTunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[derive(Debug)]
+struct ServerUnreachable {
+    addr: String,
+    source: std::io::Error,
+}
+
+impl std::fmt::Display for ServerUnreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "could not reach server {}", self.addr)
+    }
+}
+
+/// Returned when a program started by `--run` exits unsuccessfully; the caller should exit
+/// with the same code
+#[derive(Debug)]
+pub struct ChildExit {
+    pub code: i32,
+}
+
+impl std::fmt::Display for ChildExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "process exited with code {}", self.code)
+    }
+}
+
+impl std::error::Error for ChildExit {}
+
+impl std::error::Error for ServerUnreachable {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+#[derive(Debug)]
+struct RateLimited {
+    retry_after_secs: u32,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server is rate limiting builds; retry after {}s", self.retry_after_secs)
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+#[derive(Debug)]
+struct MetadataChecksumMismatch {
+    expected: [u8; 32],
+    actual: [u8; 32],
+}
+
+impl std::fmt::Display for MetadataChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hex = |hash: &[u8; 32]| hash[..4].iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        write!(
+            f,
+            "server saw workspace metadata {}.., expected {}..",
+            hex(&self.actual),
+            hex(&self.expected)
+        )
+    }
+}
+
+impl std::error::Error for MetadataChecksumMismatch {}
+
+#[derive(Debug)]
+struct UnknownDictionary {
+    dictionary_id: u32,
+}
+
+impl std::fmt::Display for UnknownDictionary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server does not have zstd dictionary {}", self.dictionary_id)
+    }
+}
+
+impl std::error::Error for UnknownDictionary {}
+
+#[derive(Serialize, Deserialize)]
+struct CachedMetadata {
+    cache_key: String,
+    metadata: Metadata,
+}
+
+/// Set in the daemon process so forwarded builds run locally instead of being forwarded again
+static IN_DAEMON: AtomicBool = AtomicBool::new(false);
+/// Metadata the daemon keeps between builds, keyed by cache key and whether dependencies were resolved
+static DAEMON_METADATA: OnceLock<std::sync::Mutex<HashMap<(String, bool), Metadata>>> = OnceLock::new();
+
+#[derive(Serialize, Deserialize)]
+enum DaemonRequest {
+    /// Arguments as the client parsed them, with its own environment
+    Run {
+        args: Box<CliArgs>,
+        cwd: PathBuf,
+        env: HashMap<String, String>,
+    },
+    Ping,
+    Stop,
+}
+
+#[derive(Serialize, Deserialize)]
+enum DaemonResponse {
+    /// Build output, streamed until the build is `Finished`
+    Output {
+        stream: OutputStream,
+        text: String,
+    },
+    Finished {
+        error: Option<String>,
+        exit_code: Option<i32>,
+    },
+    Pong {
+        pid: u32,
+    },
+    Stopped,
+}
+
+struct BuildProgress {
+    package_bar: ProgressBar,
+    build_output: Vec<String>,
+}
+
+/// Aborts a background task when dropped, so every exit path of the owner stops it
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub struct TesseractClient {
+    args: CliArgs,
+    /// Stored tokens per server, looked up from the keychain on first connection to each
+    auth_tokens: std::sync::Mutex<HashMap<String, Option<String>>>,
+    version_checked: AtomicBool,
+    warning_count: AtomicUsize,
+    /// Times each warning message has been seen, for --deduplicate-warnings
+    seen_warnings: std::sync::Mutex<HashMap<String, usize>>,
+    suppressed_warnings: AtomicUsize,
+    workspace_path: PathBuf,
+    progress: Arc<Mutex<HashMap<String, BuildProgress>>>,
+    multi_progress: MultiProgress,
+    build_history_lock: Mutex<()>,
+    connections: Semaphore,
+    /// Computed once so every package sees the same timestamp
+    auto_build_metadata: OnceLock<Vec<(String, String)>>,
+    /// Full dependency resolve, shared by every check in a single build
+    resolved_metadata: OnceLock<Metadata>,
+    /// (package, source tarball SHA-256, local artifact paths) for each package built
+    built_artifacts: Mutex<Vec<(String, String, Vec<PathBuf>)>>,
+    display: DisplayConfig,
+    /// Environment of the invoking process for daemon-served builds; `None` uses this process's
+    client_env: Option<HashMap<String, String>>,
+}
+
+impl TesseractClient {
+    pub fn new(args: CliArgs) -> Result<Self> {
+        Self::with_environment(args, std::env::current_dir()?, None)
+    }
+
+    /// A client that runs in another process's directory and environment, as the daemon
+    /// does for forwarded builds, without touching this process's own
+    fn with_environment(
+        args: CliArgs,
+        workspace_path: PathBuf,
+        client_env: Option<HashMap<String, String>>,
+    ) -> Result<Self> {
+        let connections = Semaphore::new(args.max_concurrent_connections as usize);
+        let display = DisplayConfig { spinner: args.spinner_style };
+        let client = Self {
+            args,
+            auth_tokens: std::sync::Mutex::new(HashMap::new()),
+            version_checked: AtomicBool::new(false),
+            warning_count: AtomicUsize::new(0),
+            seen_warnings: std::sync::Mutex::new(HashMap::new()),
+            suppressed_warnings: AtomicUsize::new(0),
+            workspace_path,
+            progress: Arc::new(Mutex::new(HashMap::new())),
+            multi_progress: MultiProgress::new(),
+            build_history_lock: Mutex::new(()),
+            connections,
+            auto_build_metadata: OnceLock::new(),
+            resolved_metadata: OnceLock::new(),
+            built_artifacts: Mutex::new(Vec::new()),
+            display,
+            client_env,
+        };
+        client.spawn_artifact_cache_cleanup();
+        Ok(client)
+    }
+
+    fn env_var(&self, key: &str) -> Option<String> {
+        match self.client_env {
+            Some(ref env) => env.get(key).cloned(),
+            None => std::env::var(key).ok(),
+        }
+    }
+
+    /// A child process in the workspace, with the invoking process's environment
+    fn command(&self, program: impl AsRef<std::ffi::OsStr>) -> std::process::Command {
+        let mut command = std::process::Command::new(program);
+        command.current_dir(&self.workspace_path);
+        if let Some(ref env) = self.client_env {
+            command.env_clear().envs(env);
+        }
+        command
+    }
+
+    fn metadata_command(&self) -> Result<MetadataCommand> {
+        let mut command = MetadataCommand::new();
+        command.current_dir(&self.workspace_path);
+        if let Some(cargo) = self.metadata_cargo()? {
+            command.cargo_path(cargo);
+        }
+        for (key, value) in self.client_env.iter().flatten() {
+            command.env(key, value);
+        }
+        Ok(command)
+    }
+
+    /// Prunes the artifact cache in the background so it never delays the build
+    fn spawn_artifact_cache_cleanup(&self) {
+        if self.args.max_artifact_age_days == 0 {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let cache_dir = self.artifact_cache_dir();
+        let max_age = Duration::from_secs(self.args.max_artifact_age_days * 24 * 60 * 60);
+        runtime.spawn(async move {
+            if let Err(e) = Self::remove_old_cache_entries(&cache_dir, max_age).await {
+                debug!("Artifact cache cleanup failed: {:#}", e);
+            }
+        });
+    }
+
+    async fn remove_old_cache_entries(cache_dir: &Path, max_age: Duration) -> Result<()> {
+        let mut entries = match tokio::fs::read_dir(cache_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let now = std::time::SystemTime::now();
+        while let Some(entry) = entries.next_entry().await? {
+            let last_used = match tokio::fs::read_to_string(entry.path().join(".last-used")).await {
+                Ok(secs) => secs.trim().parse().ok().map(|secs| std::time::UNIX_EPOCH + Duration::from_secs(secs)),
+                Err(_) => None,
+            };
+            let last_used = match last_used {
+                Some(last_used) => last_used,
+                None => entry.metadata().await?.modified()?,
+            };
+            if now.duration_since(last_used).unwrap_or_default() > max_age {
+                tokio::fs::remove_dir_all(entry.path()).await?;
+                info!("Removed expired artifact cache entry {}", entry.file_name().to_string_lossy());
+            }
+        }
+        Ok(())
+    }
+
+    fn create_progress_bar(&self, msg: &str) -> ProgressBar {
+        let pb = self.multi_progress.add(ProgressBar::new(100));
+        pb.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner:.green} {msg}")
+                .unwrap()
+                .tick_chars(self.display.spinner.tick_chars()),
+        );
+        pb.set_message(msg.to_string());
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb
+    }
+
+    fn build_history_path(&self) -> PathBuf {
+        self.tesseract_dir().join("build-history.json")
+    }
+
+    fn load_build_history(&self) -> HashMap<String, Vec<f64>> {
+        std::fs::read(self.build_history_path())
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    async fn record_build_duration(&self, package_name: &str, duration: Duration) -> Result<()> {
+        let _guard = self.build_history_lock.lock().await;
+        let mut history = self.load_build_history();
+        let durations = history.entry(package_name.to_string()).or_default();
+        durations.push(duration.as_secs_f64());
+        if durations.len() > BUILD_HISTORY_LEN {
+            durations.drain(..durations.len() - BUILD_HISTORY_LEN);
+        }
+        std::fs::create_dir_all(self.tesseract_dir())?;
+        std::fs::write(self.build_history_path(), serde_json::to_vec_pretty(&history)?)?;
+        Ok(())
+    }
+
+    /// Switches `bar` to an ETA bar sized by the average past build time; `None` on a first build
+    fn start_eta(&self, package_name: &str, bar: &ProgressBar) -> Option<AbortOnDrop> {
+        let history = self.load_build_history();
+        let durations = history.get(package_name).filter(|d| !d.is_empty())?;
+        let average = durations.iter().sum::<f64>() / durations.len() as f64;
+
+        bar.set_length(average.ceil().max(1.0) as u64);
+        bar.set_position(0);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("{spinner:.green} [{bar:30.cyan/blue}] eta {eta} {msg}")
+                .unwrap()
+                .tick_chars(self.display.spinner.tick_chars())
+                .progress_chars("=> "),
+        );
+
+        let bar = bar.clone();
+        let started = std::time::Instant::now();
+        Some(AbortOnDrop(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                // Hold just short of the end when a build runs longer than usual
+                let length = bar.length().unwrap_or(1);
+                bar.set_position(started.elapsed().as_secs().min(length.saturating_sub(1)));
+            }
+        })))
+    }
+
+    fn read_gitignore(path: &Path) -> Vec<String> {
+        let mut patterns = vec![
+            ".git".to_string(),
+            "target".to_string(),
+            "Cargo.lock".to_string(),
+        ];
+
+        for ignore_file in [".gitignore", ".tesseractignore"] {
+            if let Ok(content) = std::fs::read_to_string(path.join(ignore_file)) {
+                patterns.extend(content
+                    .lines()
+                    .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+                    .map(|line| line.trim().to_string()));
+            }
+        }
+        patterns
+    }
+
+    fn is_ignored(path: &Path, workspace_root: &Path, ignore_patterns: &[String]) -> bool {
+        let relative_path = path.strip_prefix(workspace_root)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|_| path.to_string_lossy().to_string());
+
+        for pattern in ignore_patterns {
+            let pattern = pattern.trim_matches('/');
+            if pattern.contains('*') {
+                let regex_pattern = pattern
+                    .replace(".", "\\.")
+                    .replace("**/", "(.*/)?")
+                    .replace("*", "[^/]*");
+                if let Ok(regex) = regex::Regex::new(&format!("^{}$", regex_pattern)) {
+                    if regex.is_match(&relative_path) {
+                        return true;
+                    }
+                }
+            } else if relative_path.contains(pattern) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// `[dependencies]`-style tables of a manifest, including target-specific and workspace ones
+    fn dependency_tables(manifest: &mut toml::Table) -> Vec<&mut toml::Table> {
+        const KINDS: [&str; 3] = ["dependencies", "dev-dependencies", "build-dependencies"];
+        let mut tables = Vec::new();
+        for (key, value) in manifest.iter_mut() {
+            let Some(value) = value.as_table_mut() else {
+                continue;
+            };
+            match key.as_str() {
+                kind if KINDS.contains(&kind) => tables.push(value),
+                "target" => {
+                    for (_, cfg) in value.iter_mut() {
+                        let Some(cfg) = cfg.as_table_mut() else {
+                            continue;
+                        };
+                        for (kind, table) in cfg.iter_mut() {
+                            if let (true, Some(table)) = (KINDS.contains(&kind.as_str()), table.as_table_mut()) {
+                                tables.push(table);
+                            }
+                        }
+                    }
+                }
+                "workspace" => {
+                    if let Some(toml::Value::Table(table)) = value.get_mut("dependencies") {
+                        tables.push(table);
+                    }
+                }
+                _ => {}
+            }
+        }
+        tables
+    }
+
+    fn read_manifest(dir: &Path) -> Result<toml::Table> {
+        let path = dir.join("Cargo.toml");
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Path dependencies outside the workspace, found recursively, as (canonical dir, package name)
+    fn outside_path_deps(&self, workspace_root: &Path) -> Result<Vec<(PathBuf, String)>> {
+        let workspace_root = workspace_root.canonicalize()?;
+        let mut queue: Vec<PathBuf> = self
+            .workspace_metadata()?
+            .packages
+            .iter()
+            .flat_map(|package| &package.dependencies)
+            .filter_map(|dependency| dependency.path.as_ref())
+            .map(PathBuf::from)
+            .collect();
+
+        let mut found: Vec<(PathBuf, String)> = Vec::new();
+        while let Some(dir) = queue.pop() {
+            let Ok(dir) = dir.canonicalize() else {
+                continue;
+            };
+            if dir.starts_with(&workspace_root) || found.iter().any(|(seen, _)| *seen == dir) {
+                continue;
+            }
+
+            let mut manifest = Self::read_manifest(&dir)?;
+            let name = manifest
+                .get("package")
+                .and_then(|package| package.get("name"))
+                .and_then(toml::Value::as_str)
+                .ok_or_else(|| anyhow::anyhow!("No package name in {}", dir.join("Cargo.toml").display()))?
+                .to_string();
+            for table in Self::dependency_tables(&mut manifest) {
+                queue.extend(
+                    table
+                        .values()
+                        .filter_map(|dependency| dependency.get("path")?.as_str())
+                        .map(|path| dir.join(path)),
+                );
+            }
+            found.push((dir, name));
+        }
+        Ok(found)
+    }
+
+    /// Copies out-of-workspace path dependencies into `temp_path/path-deps/<name>` and points
+    /// every packed Cargo.toml at the copies
+    fn pack_path_deps(&self, workspace_root: &Path, temp_path: &Path) -> Result<()> {
+        let path_deps = self.outside_path_deps(workspace_root)?;
+        if path_deps.is_empty() {
+            return Ok(());
+        }
+
+        let mut roots = vec![(workspace_root.to_path_buf(), temp_path.to_path_buf())];
+        for (dir, name) in &path_deps {
+            let dest_root = temp_path.join("path-deps").join(name);
+            let ignore_patterns = Self::read_gitignore(dir);
+            for entry in WalkDir::new(dir) {
+                let entry = entry?;
+                if Self::is_ignored(entry.path(), dir, &ignore_patterns) {
+                    continue;
+                }
+                let dest_path = dest_root.join(entry.path().strip_prefix(dir)?);
+                if entry.file_type().is_dir() {
+                    std::fs::create_dir_all(&dest_path)?;
+                } else {
+                    std::fs::copy(entry.path(), &dest_path)?;
+                }
+            }
+            info!("Copied path dependency {} -> path-deps/{}", dir.display(), name);
+            roots.push((dir.clone(), dest_root));
+        }
+
+        for (original_root, packed_root) in &roots {
+            let manifests = WalkDir::new(packed_root)
+                .into_iter()
+                .filter_entry(|entry| packed_root != temp_path || !entry.path().starts_with(temp_path.join("path-deps")))
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name() == "Cargo.toml");
+            for entry in manifests {
+                let packed_dir = entry.path().parent().unwrap();
+                let original_dir = original_root.join(packed_dir.strip_prefix(packed_root)?);
+                let mut manifest = Self::read_manifest(packed_dir)?;
+
+                let mut changed = false;
+                for table in Self::dependency_tables(&mut manifest) {
+                    for dependency in table.iter_mut().filter_map(|(_, dependency)| dependency.as_table_mut()) {
+                        let Some(path) = dependency.get("path").and_then(toml::Value::as_str) else {
+                            continue;
+                        };
+                        let Ok(resolved) = original_dir.join(path).canonicalize() else {
+                            continue;
+                        };
+                        if let Some((_, name)) = path_deps.iter().find(|(dir, _)| *dir == resolved) {
+                            let depth = packed_dir.strip_prefix(temp_path)?.components().count();
+                            let new_path = format!("{}path-deps/{}", "../".repeat(depth), name);
+                            dependency.insert("path".to_string(), toml::Value::String(new_path));
+                            changed = true;
+                        }
+                    }
+                }
+                if changed {
+                    std::fs::write(entry.path(), toml::to_string(&manifest)?)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn tarball_workspace_root(unit: &BuildUnit) -> Result<PathBuf> {
+        Ok(unit.source_files.iter()
+            .filter(|p| p.ends_with("Cargo.toml"))
+            .filter_map(|p| p.parent())
+            .min_by_key(|p| p.components().count())
+            .ok_or_else(|| anyhow::anyhow!("Could not find workspace root"))?
+            .to_path_buf())
+    }
+
+    fn create_tarball(&self, unit: &BuildUnit) -> Result<Vec<u8>> {
+        let all_manifests: Vec<_> = unit.source_files.iter()
+            .filter(|p| p.ends_with("Cargo.toml"))
+            .collect();
+
+        let workspace_root = Self::tarball_workspace_root(unit)?;
+
+        // Find package root by parsing Cargo.toml files
+        let package_root = all_manifests.iter()
+            .filter_map(|p| {
+                let dir = p.parent()?;
+                if let Ok(content) = std::fs::read_to_string(p) {
+                    if content.contains(&format!("name = \"{}\"", unit.package_name)) {
+                        return Some(dir.to_path_buf());
+                    }
+                }
+                None
+            })
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Could not find package root"))?;
+
+        info!("Creating tarball:");
+        info!("Workspace root: {}", workspace_root.display());
+        info!("Package root: {}", package_root.display());
+
+        // Read gitignore patterns
+        let ignore_patterns = Self::read_gitignore(&workspace_root);
+        // Its variables are sent in the request, so the file itself stays local
+        let dotenv_file = self.args.inject_env_from_file.as_ref().and_then(|path| path.canonicalize().ok());
+
+        // Create temporary directory for staging
+        let temp_dir = tempfile::tempdir()?;
+        let temp_path = temp_dir.path();
+
+        // Copy workspace files
+        for entry in walkdir::WalkDir::new(&workspace_root) {
+            let entry = entry?;
+            let path = entry.path();
+
+            if Self::is_ignored(path, &workspace_root, &ignore_patterns) || dotenv_file.as_deref() == Some(path) {
+                continue;
+            }
+
+            let relative_path = path.strip_prefix(&workspace_root)?;
+            let dest_path = temp_path.join(relative_path);
+
+            if entry.file_type().is_dir() {
+                std::fs::create_dir_all(&dest_path)?;
+            } else {
+                let size = entry.metadata()?.len();
+                let limit = self.args.max_source_file_size_kb * 1024;
+                if limit > 0 && size > limit {
+                    let message = format!(
+                        "{} is {} KiB, over the {} KiB --max-source-file-size-kb limit; add it to .tesseractignore",
+                        relative_path.display(),
+                        size / 1024,
+                        self.args.max_source_file_size_kb
+                    );
+                    if self.args.strict_size {
+                        return Err(anyhow::anyhow!(message));
+                    }
+                    warn!("Skipping {}", message);
+                    continue;
+                }
+                if let Some(parent) = dest_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::copy(path, &dest_path)?;
+                info!("Copied: {} -> {}", relative_path.display(), dest_path.display());
+            }
+        }
+
+        // Copy prebuilt extern crates
+        for (name, path) in &self.args.externs {
+            let file_name = path
+                .file_name()
+                .ok_or_else(|| anyhow::anyhow!("Invalid --extern path for {}: {}", name, path.display()))?;
+            let dest_path = temp_path.join("prebuilt").join(file_name);
+            std::fs::create_dir_all(temp_path.join("prebuilt"))?;
+            std::fs::copy(path, &dest_path)
+                .with_context(|| format!("Failed to copy --extern {}={}", name, path.display()))?;
+            info!("Copied prebuilt crate {}: {}", name, path.display());
+        }
+
+        // Copy directories from outside the workspace
+        for dir in &self.args.extra_include_dir {
+            let name = dir
+                .file_name()
+                .ok_or_else(|| anyhow::anyhow!("Invalid --extra-include-dir: {}", dir.display()))?;
+            let dest_root = temp_path.join("extra").join(name);
+            let mut total_size = 0;
+            for entry in WalkDir::new(dir) {
+                let entry = entry.with_context(|| format!("Failed to read --extra-include-dir {}", dir.display()))?;
+                let dest_path = dest_root.join(entry.path().strip_prefix(dir)?);
+                if entry.file_type().is_dir() {
+                    std::fs::create_dir_all(&dest_path)?;
+                } else {
+                    total_size += std::fs::copy(entry.path(), &dest_path)?;
+                }
+            }
+            if total_size > EXTRA_INCLUDE_DIR_WARN_SIZE {
+                warn!(
+                    "--extra-include-dir {} is {} MB; consider narrowing it",
+                    dir.display(),
+                    total_size / (1024 * 1024)
+                );
+            }
+            info!("Copied extra include dir {} -> extra/{}", dir.display(), name.to_string_lossy());
+        }
+
+        if self.args.include_path_deps {
+            self.pack_path_deps(&workspace_root, temp_path)?;
+        }
+
+        // List final directory structure
+        info!("Final directory structure:");
+        for entry in walkdir::WalkDir::new(temp_path).into_iter().flatten() {
+            if let Ok(relative) = entry.path().strip_prefix(temp_path) {
+                info!("  {}", relative.display());
+            }
+        }
+
+        // Create uncompressed tarball; compression happens in compress_tarball
+        let mut tar = Builder::new(Vec::new());
+        tar.append_dir_all(".", temp_path)?;
+        Ok(tar.into_inner()?)
+    }
+
+    /// Hash of everything `create_tarball` would pack, plus the flags that change what the server builds
+    fn source_tree_hash(&self, unit: &BuildUnit) -> Result<String> {
+        let workspace_root = Self::tarball_workspace_root(unit)?;
+        let ignore_patterns = Self::read_gitignore(&workspace_root);
+
+        // One file per package, since packages are hashed concurrently with --parallel-packages
+        let mtimes_path = self.tesseract_dir().join("file-mtimes").join(format!("{}.json", unit.package_name));
+        let previous: HashMap<String, FileFingerprint> = std::fs::read(&mtimes_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Vec<FileFingerprint>>(&data).ok())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|fingerprint| (fingerprint.path.clone(), fingerprint))
+            .collect();
+        let mut current = Vec::new();
+        // Only read files whose mtime or size changed since the last build
+        let mut file_hash = |path: &Path| -> Result<String> {
+            let metadata = std::fs::metadata(path)?;
+            let mtime = metadata.modified()?.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+            let key = path.to_string_lossy().into_owned();
+            let sha256 = match previous.get(&key) {
+                Some(previous) if previous.mtime_secs == mtime.as_secs() as i64
+                    && previous.mtime_nsecs == mtime.subsec_nanos()
+                    && previous.size == metadata.len() => previous.sha256.clone(),
+                _ => Sha256::digest(std::fs::read(path)?).iter().map(|b| format!("{:02x}", b)).collect(),
+            };
+            current.push(FileFingerprint {
+                path: key,
+                mtime_secs: mtime.as_secs() as i64,
+                mtime_nsecs: mtime.subsec_nanos(),
+                size: metadata.len(),
+                sha256: sha256.clone(),
+            });
+            Ok(sha256)
+        };
+
+        // Left out of the tarball by create_tarball
+        let dotenv_file = self.args.inject_env_from_file.as_ref().and_then(|path| path.canonicalize().ok());
+        let mut hasher = Sha256::new();
+        hasher.update(format!(
+            "release={} target={:?} features={:?} zstd_dict={} incremental={:?} sparse_registry={} target_features={:?} \
+             max_source_file_size_kb={} strict_size={} dotenv_file={:?}\0",
+            self.args.release,
+            self.args.target,
+            self.args.features,
+            self.args.zstd_dict,
+            self.incremental(),
+            self.args.sparse_registry,
+            self.args.target_features,
+            self.args.max_source_file_size_kb,
+            self.args.strict_size,
+            dotenv_file
+        ));
+        for entry in WalkDir::new(&workspace_root).sort_by_file_name() {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type().is_dir()
+                || Self::is_ignored(path, &workspace_root, &ignore_patterns)
+                || dotenv_file.as_deref() == Some(path)
+            {
+                continue;
+            }
+            hasher.update(path.strip_prefix(&workspace_root)?.to_string_lossy().as_bytes());
+            hasher.update([0]);
+            hasher.update(file_hash(path)?);
+        }
+        for (name, path) in &self.args.externs {
+            hasher.update(name.as_bytes());
+            hasher.update(file_hash(path)?);
+        }
+        let path_deps = if self.args.include_path_deps {
+            self.outside_path_deps(&workspace_root)?
+        } else {
+            Vec::new()
+        };
+        let extra_dirs = self.args.extra_include_dir.iter().chain(path_deps.iter().map(|(dir, _)| dir));
+        for dir in extra_dirs {
+            for entry in WalkDir::new(dir).sort_by_file_name() {
+                let entry = entry?;
+                if entry.file_type().is_file() {
+                    hasher.update(entry.path().to_string_lossy().as_bytes());
+                    hasher.update([0]);
+                    hasher.update(file_hash(entry.path())?);
+                }
+            }
+        }
+
+        if let Err(e) = std::fs::create_dir_all(mtimes_path.parent().unwrap())
+            .and_then(|_| std::fs::write(&mtimes_path, serde_json::to_vec(&current)?))
+        {
+            warn!("Failed to save {}: {}", mtimes_path.display(), e);
+        }
+        Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    fn last_fingerprint_path(&self) -> PathBuf {
+        self.tesseract_dir().join("last-fingerprint.json")
+    }
+
+    /// SHA-256 of every file that would be uploaded, keyed by path relative to the workspace root
+    fn workspace_fingerprint(&self) -> Result<BTreeMap<String, String>> {
+        let workspace_root = self.workspace_metadata()?.workspace_root.into_std_path_buf();
+        let ignore_patterns = Self::read_gitignore(&workspace_root);
+
+        let mut fingerprint = BTreeMap::new();
+        for entry in WalkDir::new(&workspace_root) {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type().is_dir() || Self::is_ignored(path, &workspace_root, &ignore_patterns) {
+                continue;
+            }
+            fingerprint.insert(
+                path.strip_prefix(&workspace_root)?.to_string_lossy().into_owned(),
+                Sha256::digest(std::fs::read(path)?).iter().map(|b| format!("{:02x}", b)).collect(),
+            );
+        }
+        Ok(fingerprint)
+    }
+
+    fn print_fingerprint_diff(&self, current: &BTreeMap<String, String>) {
+        let Some(previous) = std::fs::read(self.last_fingerprint_path())
+            .ok()
+            .and_then(|data| serde_json::from_slice::<BTreeMap<String, String>>(&data).ok())
+        else {
+            outln!("No previous build to compare against; {} files will be uploaded", current.len());
+            return;
+        };
+
+        let changed: Vec<_> = current
+            .iter()
+            .filter(|(path, hash)| previous.get(*path).is_some_and(|previous| previous != *hash))
+            .map(|(path, _)| path)
+            .collect();
+        let added: Vec<_> = current.keys().filter(|path| !previous.contains_key(*path)).collect();
+        let deleted: Vec<_> = previous.keys().filter(|path| !current.contains_key(*path)).collect();
+
+        let files = |n: usize| if n == 1 { "file" } else { "files" };
+        outln!(
+            "Changed: {} {}, Added: {} {}, Deleted: {} {}",
+            changed.len(),
+            files(changed.len()),
+            added.len(),
+            files(added.len()),
+            deleted.len(),
+            files(deleted.len())
+        );
+        for path in changed {
+            outln!("  {} {}", "M".yellow(), path);
+        }
+        for path in added {
+            outln!("  {} {}", "A".green(), path);
+        }
+        for path in deleted {
+            outln!("  {} {}", "D".red(), path);
+        }
+    }
+
+    fn last_tarball_hashes_path(&self) -> PathBuf {
+        self.tesseract_dir().join("last-tarball-hash.json")
+    }
+
+    fn last_upload_path(&self, unit: &BuildUnit) -> PathBuf {
+        self.tesseract_dir().join("last-upload").join(format!("{}.bin", unit.package_name))
+    }
+
+    fn load_cached_tarball(&self, unit: &BuildUnit, tree_hash: &str) -> Option<CachedTarball> {
+        let hashes: HashMap<String, String> =
+            serde_json::from_slice(&std::fs::read(self.last_tarball_hashes_path()).ok()?).ok()?;
+        if hashes.get(&unit.package_name).map(String::as_str) != Some(tree_hash) {
+            return None;
+        }
+        bincode::deserialize(&std::fs::read(self.last_upload_path(unit)).ok()?).ok()
+    }
+
+    fn save_cached_tarball(&self, unit: &BuildUnit, tree_hash: String, cached: &CachedTarball) -> Result<()> {
+        let blob_path = self.last_upload_path(unit);
+        if let Some(parent) = blob_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&blob_path, bincode::serialize(cached)?)?;
+
+        let hashes_path = self.last_tarball_hashes_path();
+        let mut hashes: HashMap<String, String> = std::fs::read(&hashes_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        hashes.insert(unit.package_name.clone(), tree_hash);
+        std::fs::write(&hashes_path, serde_json::to_vec_pretty(&hashes)?)?;
+        Ok(())
+    }
+
+    /// Compressed source tarball for `unit`, reused from the last upload when nothing changed
+    fn source_tarball(&self, unit: &BuildUnit) -> Result<CachedTarball> {
+        let tree_hash = if self.args.no_tarball_rebuild || self.args.watch {
+            Some(self.source_tree_hash(unit).context("Failed to hash source tree")?)
+        } else {
+            None
+        };
+
+        if let Some(ref tree_hash) = tree_hash {
+            let limit = self.args.max_tarball_size_mb * 1024 * 1024;
+            if let Some(cached) = self.load_cached_tarball(unit, tree_hash)
+                .filter(|cached| limit == 0 || cached.data.len() as u64 <= limit)
+            {
+                info!("Sources unchanged, reusing last tarball for {}", unit.package_name);
+                return Ok(cached);
+            }
+        }
+
+        info!("Creating tarball for {}", unit.package_name);
+        let tar = self.create_tarball(unit)
+            .context("Failed to create source tarball")?;
+        let (data, compression) = self.compress_tarball(&tar)
+            .context("Failed to compress source tarball")?;
+        info!("Created tarball of {} bytes", data.len());
+        self.check_tarball_size(&tar, &data)?;
+
+        let tarball = CachedTarball {
+            source_sha256: Sha256::digest(&tar).into(),
+            compression,
+            data,
+        };
+        if let Some(tree_hash) = tree_hash {
+            if let Err(e) = self.save_cached_tarball(unit, tree_hash, &tarball) {
+                warn!("Failed to cache tarball for {}: {:#}", unit.package_name, e);
+            }
+        }
+        Ok(tarball)
+    }
+
+    fn largest_tarball_entries(tar: &[u8], count: usize) -> Result<Vec<(PathBuf, u64)>> {
+        let mut entries = tar::Archive::new(tar)
+            .entries()?
+            .map(|entry| {
+                let entry = entry?;
+                Ok((entry.path()?.into_owned(), entry.size()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+        entries.truncate(count);
+        Ok(entries)
+    }
+
+    fn check_tarball_size(&self, tar: &[u8], tarball: &[u8]) -> Result<()> {
+        let limit = self.args.max_tarball_size_mb * 1024 * 1024;
+        if limit == 0 || tarball.len() as u64 <= limit {
+            return Ok(());
+        }
+
+        errln!("{}", "Largest files in the source tarball:".yellow());
+        let largest = Self::largest_tarball_entries(tar, 10)?;
+        for (path, size) in &largest {
+            errln!("  {:>12} bytes  {}", size, path.display());
+        }
+
+        Err(anyhow::anyhow!(
+            "Source tarball is {} MB, exceeding the {} MB limit. Add large files such as {} to .tesseractignore, \
+             or raise the limit with --max-tarball-size-mb (0 disables it)",
+            tarball.len() as u64 / (1024 * 1024),
+            self.args.max_tarball_size_mb,
+            largest
+                .first()
+                .map(|(path, _)| path.display().to_string())
+                .unwrap_or_default()
+        ))
+    }
+
+    fn tesseract_dir(&self) -> PathBuf {
+        self.workspace_path.join("target").join("tesseract")
+    }
+
+    fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        Ok(encoder.finish()?)
+    }
+
+    /// Gzip with pigz; `None` if pigz isn't installed
+    fn pigz(&self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut child = match self.command("pigz")
+            .arg("-c")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut stdin = child.stdin.take().unwrap();
+        // Feed stdin from another thread so a full stdout pipe can't deadlock us
+        let output = std::thread::scope(|scope| {
+            let writer = scope.spawn(move || stdin.write_all(data));
+            let output = child.wait_with_output();
+            writer.join().unwrap()?;
+            output
+        })?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!("pigz failed with {}", output.status));
+        }
+        Ok(Some(output.stdout))
+    }
+
+    fn compress_tarball(&self, tar: &[u8]) -> Result<(Vec<u8>, TarballCompression)> {
+        let started = std::time::Instant::now();
+        let (compressed, compression) = self.compress_tarball_with(tar)?;
+        if self.args.verbose {
+            outln!(
+                "{}",
+                format!(
+                    "Compressed tarball {} -> {} bytes ({:.1}%) in {:.2?}",
+                    tar.len(),
+                    compressed.len(),
+                    100.0 * compressed.len() as f64 / tar.len().max(1) as f64,
+                    started.elapsed()
+                )
+                .cyan()
+            );
+        }
+        Ok((compressed, compression))
+    }
+
+    fn compress_tarball_with(&self, tar: &[u8]) -> Result<(Vec<u8>, TarballCompression)> {
+        if self.args.zstd_dict {
+            if let Some((dictionary_id, dictionary)) = self.zstd_dictionary(tar)? {
+                let mut compressor = zstd::bulk::Compressor::with_dictionary(ZSTD_LEVEL, &dictionary)?;
+                if self.args.parallel_tarball_compress {
+                    let workers = std::thread::available_parallelism().map_or(1, |n| n.get()) as u32;
+                    if let Err(e) = compressor.set_parameter(zstd::zstd_safe::CParameter::NbWorkers(workers)) {
+                        warn!("Multithreaded zstd unavailable, compressing on one thread: {}", e);
+                    }
+                }
+                let compressed = compressor.compress(tar)?;
+
+                if self.args.verbose {
+                    let plain = zstd::bulk::compress(tar, ZSTD_LEVEL)?;
+                    outln!(
+                        "{}",
+                        format!(
+                            "Tarball: {} bytes raw, {} bytes zstd, {} bytes zstd+dict ({:.1}% smaller with dictionary {})",
+                            tar.len(),
+                            plain.len(),
+                            compressed.len(),
+                            100.0 * (1.0 - compressed.len() as f64 / plain.len().max(1) as f64),
+                            dictionary_id
+                        )
+                        .cyan()
+                    );
+                }
+
+                return Ok((
+                    compressed,
+                    TarballCompression::Zstd { dictionary_id },
+                ));
+            }
+        }
+
+        if self.args.parallel_tarball_compress {
+            match self.pigz(tar) {
+                Ok(Some(compressed)) => return Ok((compressed, TarballCompression::Gzip)),
+                Ok(None) => warn!("pigz not found, compressing on one thread"),
+                Err(e) => warn!("pigz failed, compressing on one thread: {:#}", e),
+            }
+        }
+        Ok((Self::gzip(tar)?, TarballCompression::Gzip))
+    }
+
+    /// Trained dictionaries by ID; old ones are kept because cached tarballs may still use them
+    fn zstd_dictionary_path(&self, dictionary_id: u32) -> PathBuf {
+        self.tesseract_dir().join("zstd-dicts").join(format!("{}.dict", dictionary_id))
+    }
+
+    fn zstd_dictionary_for(&self, compression: &TarballCompression) -> Result<Option<Vec<u8>>> {
+        match *compression {
+            TarballCompression::Zstd { dictionary_id } => {
+                let path = self.zstd_dictionary_path(dictionary_id);
+                let dictionary = std::fs::read(&path)
+                    .with_context(|| format!("Failed to read zstd dictionary {}", path.display()))?;
+                Ok(Some(dictionary))
+            }
+            TarballCompression::Gzip => Ok(None),
+        }
+    }
+
+    /// Saves `sample` and returns the current dictionary, retraining it on the latest
+    /// samples every ZSTD_DICT_SAMPLES tarballs so it follows the source tree
+    fn zstd_dictionary(&self, sample: &[u8]) -> Result<Option<(u32, Vec<u8>)>> {
+        let samples_dir = self.tesseract_dir().join("dict-samples");
+        std::fs::create_dir_all(&samples_dir)?;
+        let mut indices: Vec<u64> = std::fs::read_dir(&samples_dir)?
+            .filter_map(|entry| entry.ok()?.path().file_stem()?.to_str()?.parse().ok())
+            .collect();
+        indices.sort_unstable();
+        let index = indices.last().map_or(0, |last| last + 1);
+        std::fs::write(samples_dir.join(format!("{}.tar", index)), sample)?;
+        indices.push(index);
+        while indices.len() > ZSTD_DICT_SAMPLES {
+            let _ = std::fs::remove_file(samples_dir.join(format!("{}.tar", indices.remove(0))));
+        }
+        info!("Saved zstd dictionary sample {}", index + 1);
+
+        let current_path = self.tesseract_dir().join("zstd-dicts").join("current");
+        // u64::is_multiple_of needs Rust 1.87, newer than this crate asks for
+        #[allow(clippy::manual_is_multiple_of)]
+        let retrain = (index + 1) % ZSTD_DICT_SAMPLES as u64 == 0;
+        if retrain {
+            let samples = indices
+                .iter()
+                .map(|index| Ok(std::fs::read(samples_dir.join(format!("{}.tar", index)))?))
+                .collect::<Result<Vec<_>>>()?;
+            let dictionary = zstd::dict::from_samples(&samples, ZSTD_DICT_MAX_SIZE)
+                .context("Failed to train zstd dictionary")?;
+            let dictionary_id = zstd::zstd_safe::get_dict_id_from_dict(&dictionary)
+                .map(|id| id.get())
+                .unwrap_or_default();
+            let dictionary_path = self.zstd_dictionary_path(dictionary_id);
+            std::fs::create_dir_all(dictionary_path.parent().unwrap())?;
+            std::fs::write(&dictionary_path, &dictionary)?;
+            std::fs::write(&current_path, dictionary_id.to_string())?;
+            info!(
+                "Trained zstd dictionary {} of {} bytes from {} samples",
+                dictionary_id,
+                dictionary.len(),
+                samples.len()
+            );
+        }
+
+        let Some(dictionary_id) = std::fs::read_to_string(&current_path).ok().and_then(|id| id.trim().parse().ok()) else {
+            return Ok(None);
+        };
+        Ok(Some((dictionary_id, std::fs::read(self.zstd_dictionary_path(dictionary_id))?)))
+    }
+
+    fn artifact_manifest_path(&self, package_name: &str) -> PathBuf {
+        self.tesseract_dir().join("artifacts").join(format!("{}.json", package_name))
+    }
+
+    fn save_artifact_manifest(&self, package_name: &str, paths: &[PathBuf]) -> Result<()> {
+        let manifest_path = self.artifact_manifest_path(package_name);
+        if let Some(parent) = manifest_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(manifest_path, serde_json::to_vec(paths)?)?;
+        Ok(())
+    }
+
+    fn existing_artifact_hashes(&self, unit: &BuildUnit) -> HashMap<PathBuf, [u8; 32]> {
+        // Stripped artifacts no longer match the server's copies, so deltas can't apply to them
+        if self.args.artifact_strip_debug {
+            return HashMap::new();
+        }
+        let paths: Vec<PathBuf> = std::fs::read(self.artifact_manifest_path(&unit.package_name))
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default();
+
+        paths
+            .into_iter()
+            .filter_map(|path| {
+                let data = std::fs::read(self.local_artifact_path(unit, &path)).ok()?;
+                Some((path, Sha256::digest(&data).into()))
+            })
+            .collect()
+    }
+
+    fn apply_delta(path: &Path, patch: &[u8]) -> Result<Vec<u8>> {
+        let old = std::fs::read(path)?;
+        let mut reader = bipatch::Reader::new(patch, std::io::Cursor::new(old))
+            .map_err(|e| anyhow::anyhow!("Invalid artifact delta: {}", e))?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn is_strippable(path: &Path) -> bool {
+        path.extension()
+            .is_none_or(|ext| ext == "exe" || ext == "so" || ext == "dylib" || ext == "dll")
+    }
+
+    /// Runs `strip --strip-debug`, falling back to `llvm-strip -g`, which also handles
+    /// binaries for targets the host's strip doesn't know.
+    async fn strip_debug(&self, path: &Path) -> Result<()> {
+        let path_var = self.env_var("PATH").unwrap_or_default();
+        let on_path = |tool: &str| {
+            std::env::split_paths(&path_var)
+                .any(|dir| dir.join(tool).is_file() || dir.join(format!("{}.exe", tool)).is_file())
+        };
+        let candidates = if cfg!(unix) {
+            vec![("strip", "--strip-debug"), ("llvm-strip", "-g")]
+        } else {
+            vec![("llvm-strip", "-g")]
+        };
+
+        let mut last_error = None;
+        for (tool, flag) in candidates.into_iter().filter(|(tool, _)| on_path(tool)) {
+            let mut command = tokio::process::Command::from(self.command(tool));
+            let output = command.arg(flag).arg(path).output().await?;
+            if output.status.success() {
+                debug!("Stripped {} with {}", path.display(), tool);
+                return Ok(());
+            }
+            last_error = Some(anyhow::anyhow!(
+                "{} failed: {}",
+                tool,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("Neither strip nor llvm-strip is on PATH; install binutils or LLVM")))
+    }
+
+    async fn write_artifact_safely(path: &Path, data: &[u8]) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+        tokio::fs::write(&tmp_path, data).await?;
+
+        Self::replace_file(&tmp_path, path).await
+    }
+
+    async fn replace_file(tmp_path: &Path, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        #[cfg(windows)]
+        {
+            use tokio::fs;
+            let old_path = path.with_extension(format!("{}.old", std::process::id()));
+            
+            if path.exists() {
+                match fs::rename(path, &old_path).await {
+                    Ok(_) => (),
+                    Err(e) => {
+                        fs::remove_file(&tmp_path).await?;
+                        return Err(anyhow::anyhow!(
+                            "Could not replace existing file - it may be in use: {}",
+                            e
+                        ));
+                    }
+                }
+            }
+
+            if let Err(e) = fs::rename(tmp_path, path).await {
+                if old_path.exists() {
+                    let _ = fs::rename(&old_path, path).await;
+                }
+                return Err(anyhow::anyhow!("Failed to move new file into place: {}", e));
+            }
+
+            if old_path.exists() {
+                let _ = fs::remove_file(&old_path).await;
+            }
+        }
+
+        #[cfg(not(windows))]
+        {
+            tokio::fs::rename(tmp_path, path).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Exclusive lock on target/tesseract/.lock; released when the returned file is dropped
+    async fn lock_artifacts(&self) -> Result<std::fs::File> {
+        std::fs::create_dir_all(self.tesseract_dir())?;
+        let lock_file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.tesseract_dir().join(".lock"))?;
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(self.args.lock_timeout_secs);
+        loop {
+            match FileExt::try_lock_exclusive(&lock_file) {
+                Ok(()) => return Ok(lock_file),
+                Err(_) if tokio::time::Instant::now() < deadline => {
+                    info!("Waiting for another build to finish writing artifacts");
+                    tokio::time::sleep(Duration::from_millis(250)).await;
+                }
+                Err(_) => {
+                    return Err(anyhow::anyhow!("Another build is writing artifacts; try again later."))
+                }
+            }
+        }
+    }
+
+    async fn save_artifacts(&self, unit: &BuildUnit, artifacts: Vec<ArtifactEntry>) -> Result<()> {
+        let staging_root = self.tesseract_dir().join("staging");
+        tokio::fs::create_dir_all(&staging_root).await?;
+        // Removed on drop, so a failure part way through never leaves a partial set in target/
+        let staging = tempfile::tempdir_in(&staging_root)?;
+
+        let artifact_paths: Vec<_> = artifacts.iter().map(|a| a.path.clone()).collect();
+        let mut staged = Vec::new();
+        for (index, ArtifactEntry { path, data, hash }) in artifacts.into_iter().enumerate() {
+            let target_path = self.local_artifact_path(unit, &path);
+
+            let data = match data {
+                ArtifactData::Unchanged => {
+                    info!("Artifact unchanged: {}", target_path.display());
+                    continue;
+                }
+                ArtifactData::Delta(patch) => Self::apply_delta(&target_path, &patch)
+                    .with_context(|| format!("Failed to patch artifact {}", target_path.display()))?,
+                ArtifactData::Full(data) => data,
+                ArtifactData::Chunked => {
+                    return Err(anyhow::anyhow!("Artifact {} was never downloaded", path.display()));
+                }
+            };
+
+            if self.args.artifact_hash_algorithm.hash(&data) != hash {
+                return Err(anyhow::anyhow!(
+                    "Artifact {} failed {:?} integrity check",
+                    path.display(),
+                    self.args.artifact_hash_algorithm
+                ));
+            }
+
+            let staged_path = staging.path().join(index.to_string());
+            tokio::fs::write(&staged_path, &data).await
+                .with_context(|| format!("Failed to stage artifact {}", path.display()))?;
+            // Strip the staged copy: the file in target/ may be hard-linked into the artifact cache
+            if self.args.artifact_strip_debug && Self::is_strippable(&target_path) {
+                if self.args.keep_full_artifact {
+                    let full_path = staging.path().join(format!("{}.full", index));
+                    tokio::fs::write(&full_path, &data).await?;
+                    let mut full_target = target_path.clone().into_os_string();
+                    full_target.push(".full");
+                    staged.push((full_path, PathBuf::from(full_target)));
+                }
+                self.strip_debug(&staged_path)
+                    .await
+                    .with_context(|| format!("Failed to strip debug info from {}", path.display()))?;
+            }
+            staged.push((staged_path, target_path));
+        }
+
+        let _lock = self.lock_artifacts().await?;
+        for (staged_path, target_path) in staged {
+            info!("Writing artifact to {}", target_path.display());
+            Self::replace_file(&staged_path, &target_path).await
+                .with_context(|| format!("Failed to write artifact to {}", target_path.display()))?;
+            info!("Successfully wrote artifact: {}", target_path.display());
+        }
+        self.save_artifact_manifest(&unit.package_name, &artifact_paths)
+    }
+
+    async fn upload_artifacts_to_s3(&self, destination: &S3Destination, paths: &[PathBuf]) -> Result<()> {
+        let credentials = match (self.env_var("AWS_ACCESS_KEY_ID"), self.env_var("AWS_SECRET_ACCESS_KEY")) {
+            (Some(key), Some(secret)) => match self.env_var("AWS_SESSION_TOKEN") {
+                Some(token) => rusty_s3::Credentials::new_with_token(key, secret, token),
+                None => rusty_s3::Credentials::new(key, secret),
+            },
+            _ => return Err(anyhow::anyhow!("AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY must be set")),
+        };
+        let region = self
+            .env_var("AWS_REGION")
+            .or_else(|| self.env_var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".to_string());
+        // Custom endpoints (MinIO, R2, ...) generally only support path-style URLs
+        let (endpoint, url_style) = match self.env_var("AWS_ENDPOINT_URL") {
+            Some(endpoint) => (endpoint, rusty_s3::UrlStyle::Path),
+            None => (format!("https://s3.{}.amazonaws.com", region), rusty_s3::UrlStyle::VirtualHost),
+        };
+        let bucket = rusty_s3::Bucket::new(
+            endpoint.parse().context("Invalid AWS_ENDPOINT_URL")?,
+            url_style,
+            destination.bucket.clone(),
+            region,
+        )?;
+
+        use rusty_s3::S3Action;
+
+        if self.args.no_verify_tls {
+            errln!(
+                "{}",
+                "WARNING: TLS certificate verification is disabled for S3 uploads. Do not use in production.".red().bold()
+            );
+        }
+        let http = reqwest::Client::builder()
+            .danger_accept_invalid_certs(self.args.no_verify_tls)
+            .build()?;
+        let artifact_dir = self.artifact_dir();
+        for path in paths {
+            let relative = path.strip_prefix(&artifact_dir).unwrap_or(path);
+            let key = destination.key(relative);
+            let data = tokio::fs::read(path).await
+                .with_context(|| format!("Failed to read artifact {}", path.display()))?;
+
+            let mut action = bucket.put_object(Some(&credentials), &key);
+            let headers = action.headers_mut();
+            headers.insert("content-type", "application/octet-stream");
+            if self.args.s3_public {
+                headers.insert("x-amz-acl", "public-read");
+            }
+            // Headers are part of the signature, so they must be sent exactly as signed
+            let mut request = http.put(action.sign(Duration::from_secs(3600)));
+            for (name, value) in action.headers_mut().iter() {
+                request = request.header(name, value);
+            }
+
+            request.body(data).send().await
+                .and_then(|response| response.error_for_status())
+                .with_context(|| format!("Failed to upload {}", path.display()))?;
+
+            outln!("{} {}", "Uploaded".green().bold(), bucket.object_url(&key)?);
+        }
+        Ok(())
+    }
+
+    async fn write_provenance(&self, paths: &[PathBuf], source_sha256: &[u8], toolchain: String) -> Result<()> {
+        let record = ProvenanceRecord {
+            server: self.server_addr()?.to_string(),
+            built_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            source_sha256: source_sha256.iter().map(|b| format!("{:02x}", b)).collect(),
+            toolchain,
+            rustflags: self.rustflags(),
+            features: self.args.features.clone(),
+            commit: self.git_commit(),
+        };
+        let json = serde_json::to_vec_pretty(&record)?;
+
+        for path in paths {
+            let mut sidecar = path.clone().into_os_string();
+            sidecar.push(".provenance.json");
+            Self::write_artifact_safely(Path::new(&sidecar), &json).await?;
+        }
+        Ok(())
+    }
+
+    fn git_commit(&self) -> Option<String> {
+        let output = self.command("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    fn artifact_dir(&self) -> PathBuf {
+        let mut dir = self.workspace_path.join("target");
+        if let Some(ref target) = self.args.target {
+            dir.push(target);
+        }
+        dir.join(if self.args.release { "release" } else { "debug" })
+    }
+
+    fn local_artifact_path(&self, unit: &BuildUnit, path: &Path) -> PathBuf {
+        let mut path = path.to_path_buf();
+        if let Some(ref prefix) = self.args.strip_artifact_prefix {
+            match path.strip_prefix(prefix) {
+                Ok(stripped) => path = stripped.to_path_buf(),
+                Err(_) => warn!("Artifact {} does not start with {}", path.display(), prefix.display()),
+            }
+        }
+        if let Some(ref prefix) = self.args.prepend_artifact_prefix {
+            path = prefix.join(path);
+        }
+        if self.args.output_format == OutputFormat::Cargo {
+            path = self.artifact_os().cargo_file_name(&path);
+        }
+        if self.args.versioned_artifacts {
+            path = Path::new(&format!("{}-{}", unit.package_name, unit.version)).join(path);
+        }
+        self.artifact_dir().join(path)
+    }
+
+    fn artifact_os(&self) -> ArtifactOs {
+        match self.args.target {
+            Some(ref target) if target.contains("windows") => ArtifactOs::Windows,
+            Some(ref target) if target.contains("apple") => ArtifactOs::MacOs,
+            Some(_) => ArtifactOs::Unix,
+            None if cfg!(windows) => ArtifactOs::Windows,
+            None if cfg!(target_os = "macos") => ArtifactOs::MacOs,
+            None => ArtifactOs::Unix,
+        }
+    }
+
+    fn server_addr(&self) -> Result<&str> {
+        self.args
+            .server
+            .first()
+            .map(String::as_str)
+            .ok_or_else(|| anyhow::anyhow!("No build server given; pass --server <HOST:PORT>"))
+    }
+
+    async fn connect(&self, addr: &str) -> Result<TcpStream> {
+        let stream = match self.args.bind_address {
+            Some(bind_address) => Self::connect_from(bind_address, addr).await,
+            None => TcpStream::connect(addr).await,
+        };
+        let stream = stream
+            .map_err(|source| ServerUnreachable {
+                addr: addr.to_string(),
+                source,
+            })
+            .context("Failed to connect to build server")?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    async fn connect_from(bind_address: std::net::IpAddr, addr: &str) -> std::io::Result<TcpStream> {
+        let server = tokio::net::lookup_host(addr)
+            .await?
+            .find(|server| server.is_ipv4() == bind_address.is_ipv4())
+            .ok_or_else(|| std::io::Error::new(
+                std::io::ErrorKind::AddrNotAvailable,
+                format!("{} has no address in the same family as --bind-address {}", addr, bind_address),
+            ))?;
+        let socket = if bind_address.is_ipv4() {
+            tokio::net::TcpSocket::new_v4()?
+        } else {
+            tokio::net::TcpSocket::new_v6()?
+        };
+        socket.bind(std::net::SocketAddr::new(bind_address, 0))?;
+        socket.connect(server).await
+    }
+
+    async fn send_request(stream: &mut TcpStream, request: &BuildRequest) -> Result<()> {
+        let data = bincode::serialize(request)
+            .context("Failed to serialize request")?;
+        info!("Request size: {} bytes", data.len());
+
+        let len = (data.len() as u32).to_be_bytes();
+        stream.write_all(&len).await
+            .context("Failed to send message length")?;
+        stream.write_all(&data).await
+            .context("Failed to send request")?;
+        Ok(())
+    }
+
+    async fn read_response(stream: &mut TcpStream) -> Result<BuildResponse> {
+        let mut len_buf = [0u8; 4];
+        match stream.read_exact(&mut len_buf).await {
+            Ok(_) => (),
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                    return Err(anyhow::Error::new(e).context("Server connection closed unexpectedly"));
+                }
+                return Err(e.into());
+            }
+        }
+
+        let len = u32::from_be_bytes(len_buf) as usize;
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).await?;
+
+        Ok(bincode::deserialize(&buf)?)
+    }
+
+    fn keyring_entry(server: &str) -> Result<keyring::Entry> {
+        Ok(keyring::Entry::new(KEYRING_SERVICE, server)?)
+    }
+
+    fn stored_auth_token(server: &str) -> Option<String> {
+        match Self::keyring_entry(server).and_then(|entry| Ok(entry.get_password()?)) {
+            Ok(token) => Some(token),
+            Err(e) => {
+                info!("No stored credentials for {}: {}", server, e);
+                None
+            }
+        }
+    }
+
+    fn auth_token(&self, server: &str) -> Option<String> {
+        if let Some(token) = &self.args.auth_token {
+            return Some(token.clone());
+        }
+        self.auth_tokens
+            .lock()
+            .unwrap()
+            .entry(server.to_string())
+            .or_insert_with(|| Self::stored_auth_token(server))
+            .clone()
+    }
+
+    async fn login(&self, server: &str) -> Result<()> {
+        let token = rpassword::prompt_password(format!("Auth token for {}: ", server))
+            .context("Failed to read auth token")?;
+        let token = token.trim().to_string();
+
+        let mut stream = self.connect(server).await?;
+        Self::send_request(&mut stream, &BuildRequest::Heartbeat { auth_token: Some(token.clone()) }).await?;
+        match Self::read_response(&mut stream).await? {
+            BuildResponse::HeartbeatAck => {}
+            other => return Err(anyhow::anyhow!("Server rejected the auth token: {:?}", other)),
+        }
+
+        Self::keyring_entry(server)?
+            .set_password(&token)
+            .context("Failed to store auth token in the OS keychain")?;
+        outln!("{}", format!("Logged in to {}", server).green());
+        Ok(())
+    }
+
+    fn logout(server: &str) -> Result<()> {
+        Self::keyring_entry(server)?
+            .delete_credential()
+            .with_context(|| format!("Failed to remove stored credentials for {}", server))?;
+        outln!("Logged out of {}", server);
+        Ok(())
+    }
+
+    fn extern_crates(&self) -> HashMap<String, PathBuf> {
+        self.args
+            .externs
+            .iter()
+            .filter_map(|(name, path)| {
+                Some((name.clone(), Path::new("prebuilt").join(path.file_name()?)))
+            })
+            .collect()
+    }
+
+    pub async fn check_server_version(&self) -> Result<()> {
+        let client_version = env!("CARGO_PKG_VERSION");
+        let mut stream = self.connect(self.server_addr()?).await?;
+        Self::send_request(&mut stream, &BuildRequest::Handshake {
+            client_version: client_version.to_string(),
+        }).await?;
+
+        let server_version = match Self::read_response(&mut stream).await? {
+            BuildResponse::ServerVersion { version } => version,
+            other => return Err(anyhow::anyhow!("Unexpected handshake response: {:?}", other)),
+        };
+
+        let major = |version: &str| version.split('.').next().map(str::to_string);
+        if major(client_version) != major(&server_version) {
+            return Err(anyhow::anyhow!(
+                "Client version {} is incompatible with server version {}; use --skip-version-check to build anyway",
+                client_version,
+                server_version
+            ));
+        }
+        if client_version != server_version {
+            outln!(
+                "{}",
+                format!(
+                    "Warning: client version {} differs from server version {}",
+                    client_version, server_version
+                )
+                .yellow()
+            );
+        } else {
+            info!("Server version {} matches client", server_version);
+        }
+        Ok(())
+    }
+
+    fn print_completions(shell: clap_complete::Shell) {
+        use clap::CommandFactory;
+        clap_complete::generate(shell, &mut Cargo::command(), "cargo", &mut std::io::stdout());
+    }
+
+    fn init_server(port: u16, log_dir: &Path, user: &str) {
+        let log_dir = log_dir.display();
+        outln!(
+            r#"# /etc/systemd/system/tesseract-server.service
+[Unit]
+Description=Tesseract remote build server
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=simple
+User={user}
+Group={user}
+ExecStartPre=/usr/bin/mkdir -p {log_dir}
+ExecStart=/usr/local/bin/tesseract-server --port {port}
+Restart=on-failure
+RestartSec=5
+StandardOutput=append:{log_dir}/tesseract-server.log
+StandardError=append:{log_dir}/tesseract-server.log
+LimitNOFILE=65536
+
+[Install]
+WantedBy=multi-user.target
+
+# /etc/nginx/nginx.conf (TLS termination; clients connect to port 443)
+stream {{
+    upstream tesseract {{
+        server 127.0.0.1:{port};
+    }}
+
+    server {{
+        listen 443 ssl;
+        ssl_certificate     /etc/ssl/certs/tesseract.pem;
+        ssl_certificate_key /etc/ssl/private/tesseract.key;
+        ssl_protocols       TLSv1.2 TLSv1.3;
+        proxy_timeout       1h;
+        proxy_pass          tesseract;
+    }}
+}}"#
+        );
+    }
+
+    async fn request(&self, request: &BuildRequest) -> Result<BuildResponse> {
+        let mut stream = self.connect(self.server_addr()?).await?;
+        Self::send_request(&mut stream, request).await?;
+        Self::read_response(&mut stream).await
+    }
+
+    async fn print_meta(&self, query: MetaQuery) -> Result<()> {
+        let query = query
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default();
+        match self.request(&BuildRequest::PrintMeta { query }).await? {
+            BuildResponse::MetaResult { value } => {
+                outln!("{}", value.trim());
+                Ok(())
+            }
+            other => Err(anyhow::anyhow!("Unexpected response to meta query: {:?}", other)),
+        }
+    }
+
+    fn print_tarball_contents(&self) -> Result<()> {
+        for unit in self.discover_build_units()? {
+            let tar = self.create_tarball(&unit)
+                .context("Failed to create source tarball")?;
+
+            let mut entries = Vec::new();
+            for entry in tar::Archive::new(tar.as_slice()).entries()? {
+                let entry = entry?;
+                if entry.header().entry_type().is_file() {
+                    entries.push((entry.path()?.into_owned(), entry.size()));
+                }
+            }
+            entries.sort();
+
+            outln!("{}", format!("Tarball contents for {}:", unit.package_name).bold());
+            let mut total_size = 0;
+            for (path, size) in entries {
+                outln!("{} <{} bytes>", path.display(), size);
+                total_size += size;
+            }
+            outln!("Total uncompressed size: {} bytes", total_size);
+            outln!("Estimated compressed size: {} bytes", Self::gzip(&tar)?.len());
+        }
+        Ok(())
+    }
+
+    async fn print_server_env(&self, show_sensitive: bool) -> Result<()> {
+        const RELEVANT_VARS: &[&str] = &[
+            "PATH", "RUSTUP_HOME", "RUSTUP_TOOLCHAIN", "CARGO_HOME", "CARGO_TARGET_DIR", "RUSTFLAGS",
+            "RUSTC_WRAPPER", "CC", "CXX", "AR", "LD", "CFLAGS", "CXXFLAGS", "LDFLAGS",
+            "PKG_CONFIG_PATH", "PKG_CONFIG_SYSROOT_DIR", "LD_LIBRARY_PATH", "LIBRARY_PATH",
+        ];
+        const SENSITIVE_MARKERS: &[&str] = &["TOKEN", "SECRET", "KEY", "PASSWORD"];
+
+        let vars = match self.request(&BuildRequest::CaptureEnv).await? {
+            BuildResponse::ServerEnv { vars } => vars,
+            other => return Err(anyhow::anyhow!("Unexpected response to env request: {:?}", other)),
+        };
+
+        let mut vars: Vec<_> = vars
+            .into_iter()
+            .filter(|(name, _)| {
+                RELEVANT_VARS.contains(&name.as_str())
+                    || name.starts_with("CARGO_")
+                    || name.starts_with("RUST")
+            })
+            .filter(|(name, _)| {
+                show_sensitive || !SENSITIVE_MARKERS.iter().any(|marker| name.to_uppercase().contains(marker))
+            })
+            .collect();
+        vars.sort();
+
+        let width = vars.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        for (name, value) in vars {
+            outln!("{}  {}", format!("{:width$}", name, width = width).bold(), value);
+        }
+        Ok(())
+    }
+
+    async fn list_targets(&self) -> Result<()> {
+        let (installed, available) = match self.request(&BuildRequest::ListTargets).await? {
+            BuildResponse::AvailableTargets { installed, available } => (installed, available),
+            other => return Err(anyhow::anyhow!("Unexpected response to list-targets: {:?}", other)),
+        };
+
+        outln!("{}", "Installed (can build now):".green().bold());
+        for target in &installed {
+            outln!("  {}", target);
+        }
+        outln!();
+        outln!("{}", "Available (need `rustup target add`):".yellow().bold());
+        for target in available.iter().filter(|target| !installed.contains(target)) {
+            outln!("  {}", target);
+        }
+        Ok(())
+    }
+
+    async fn update_toolchain(&self, toolchain: &str, components: &[String]) -> Result<()> {
+        let mut stream = self.connect(self.server_addr()?).await?;
+        Self::send_request(&mut stream, &BuildRequest::UpdateToolchain {
+            toolchain: toolchain.to_string(),
+            components: components.to_vec(),
+            auth_token: self.auth_token(self.server_addr()?),
+        }).await?;
+
+        loop {
+            match Self::read_response(&mut stream).await? {
+                BuildResponse::BuildOutput { output, is_error, .. } => {
+                    if is_error {
+                        errln!("{}", output.red());
+                    } else {
+                        outln!("{}", output);
+                    }
+                }
+                BuildResponse::UpdateComplete { old_version, new_version } => {
+                    if old_version == new_version {
+                        outln!("{} {} is up to date ({})", "Toolchain".green().bold(), toolchain, new_version);
+                    } else {
+                        outln!(
+                            "{} {}: {} -> {}",
+                            "Updated".green().bold(),
+                            toolchain,
+                            old_version,
+                            new_version
+                        );
+                    }
+                    return Ok(());
+                }
+                BuildResponse::BuildError { error, .. } => {
+                    return Err(anyhow::anyhow!("Toolchain update failed: {}", error));
+                }
+                other => return Err(anyhow::anyhow!("Unexpected response to update: {:?}", other)),
+            }
+        }
+    }
+
+    async fn server_stats(&self, period_secs: u64) -> Result<()> {
+        let mut stream = self.connect(self.server_addr()?).await?;
+        Self::send_request(&mut stream, &BuildRequest::GetStats {
+            period_secs,
+            auth_token: self.auth_token(self.server_addr()?),
+        }).await?;
+
+        match Self::read_response(&mut stream).await? {
+            BuildResponse::Stats {
+                total_builds,
+                successful,
+                failed,
+                avg_build_secs,
+                p95_build_secs,
+                cache_hit_rate,
+                total_data_transferred_bytes,
+            } => {
+                let success_rate = 100.0 * successful as f64 / total_builds.max(1) as f64;
+                outln!("{} (last {})", "Server build stats".bold(), Self::format_period(period_secs));
+                outln!(
+                    "  Builds     {} total, {} succeeded, {} failed ({:.1}% success)",
+                    total_builds,
+                    successful.to_string().green(),
+                    failed.to_string().red(),
+                    success_rate
+                );
+                outln!("  Duration   {:.1}s average, {:.1}s p95", avg_build_secs, p95_build_secs);
+                outln!("  Cache      {:.1}% hit rate", cache_hit_rate * 100.0);
+                outln!("  Transfer   {:.1} MB", total_data_transferred_bytes as f64 / (1024.0 * 1024.0));
+                Ok(())
+            }
+            BuildResponse::StatsUnavailable => {
+                outln!("{}", "The server does not record build metrics".yellow());
+                Ok(())
+            }
+            BuildResponse::BuildError { error, .. } => Err(anyhow::anyhow!("Fetching server stats failed: {}", error)),
+            other => Err(anyhow::anyhow!("Unexpected response to server-stats: {:?}", other)),
+        }
+    }
+
+    fn format_period(secs: u64) -> String {
+        match secs {
+            s if s % (24 * 60 * 60) == 0 => format!("{}d", s / (24 * 60 * 60)),
+            s if s % (60 * 60) == 0 => format!("{}h", s / (60 * 60)),
+            s if s % 60 == 0 => format!("{}m", s / 60),
+            s => format!("{}s", s),
+        }
+    }
+
+    async fn server_log(&self, follow: bool, lines: u32, filter: Option<LogLevel>) -> Result<()> {
+        let mut stream = self.connect(self.server_addr()?).await?;
+        Self::send_request(&mut stream, &BuildRequest::TailLog {
+            lines,
+            follow,
+            auth_token: self.auth_token(self.server_addr()?),
+        }).await?;
+
+        loop {
+            let response = match Self::read_response(&mut stream).await {
+                Ok(response) => response,
+                // The server closes the connection once it has sent the requested lines
+                Err(e) if e.root_cause().downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            match response {
+                BuildResponse::LogLine { timestamp, level, message } => {
+                    let parsed = LogLevel::from_name(&level);
+                    if filter.is_some_and(|filter| parsed.is_some_and(|parsed| parsed > filter)) {
+                        continue;
+                    }
+                    let time = chrono::DateTime::from_timestamp(timestamp as i64, 0)
+                        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+                        .unwrap_or_else(|| timestamp.to_string());
+                    let level = format!("{:>5}", level.to_uppercase());
+                    let level = match parsed {
+                        Some(LogLevel::Error) => level.red().bold(),
+                        Some(LogLevel::Warn) => level.yellow().bold(),
+                        Some(LogLevel::Info) => level.green(),
+                        Some(LogLevel::Debug) => level.blue(),
+                        Some(LogLevel::Trace) | None => level.dimmed(),
+                    };
+                    outln!("{} {} {}", time.dimmed(), level, message);
+                }
+                other => return Err(anyhow::anyhow!("Unexpected response to server-log: {:?}", other)),
+            }
+        }
+    }
+
+    async fn publish(
+        &self,
+        package: Option<&str>,
+        dry_run: bool,
+        registry: Option<String>,
+        token: Option<String>,
+    ) -> Result<()> {
+        let mut units = self.discover_build_units_filtered(&UnitFilter {
+            packages: package.map(str::to_string).into_iter().collect(),
+            ..UnitFilter::default()
+        })?;
+        let unit = match (units.len(), package) {
+            (1, _) => units.remove(0),
+            (0, Some(package)) => return Err(anyhow::anyhow!("No package named {} in the workspace", package)),
+            _ => return Err(anyhow::anyhow!("The workspace has several packages; choose one with --package")),
+        };
+
+        let tarball = self.source_tarball(&unit)?;
+        // Publishing is rare enough that the dictionary is always sent along
+        let zstd_dictionary = self.zstd_dictionary_for(&tarball.compression)?;
+        let mut stream = self.connect(self.server_addr()?).await?;
+        info!("Publishing {} {}{}", unit.package_name, unit.version, if dry_run { " (dry run)" } else { "" });
+        Self::send_request(&mut stream, &BuildRequest::Publish {
+            unit,
+            dry_run,
+            registry,
+            token,
+            auth_token: self.auth_token(self.server_addr()?),
+            tarball_compression: tarball.compression,
+            zstd_dictionary,
+            tarball_data: tarball.data,
+        }).await?;
+
+        loop {
+            match Self::read_response(&mut stream).await? {
+                BuildResponse::BuildOutput { output, is_error, .. } => {
+                    if is_error {
+                        errln!("{}", output.red());
+                    } else {
+                        outln!("{}", output);
+                    }
+                }
+                BuildResponse::PublishComplete { package_name, version } => {
+                    let verb = if dry_run { "Verified" } else { "Published" };
+                    outln!("{} {} {}", verb.green().bold(), package_name, version);
+                    return Ok(());
+                }
+                BuildResponse::BuildError { error, .. } => {
+                    return Err(anyhow::anyhow!("Publish failed: {}", error));
+                }
+                other => return Err(anyhow::anyhow!("Unexpected response to publish: {:?}", other)),
+            }
+        }
+    }
+
+    fn cargo_command(&self) -> CargoCommand {
+        match self.args.command {
+            Some(Command::Test { .. }) => CargoCommand::Test,
+            _ => CargoCommand::Build,
+        }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        #[cfg(unix)]
+        if self.forwardable_to_daemon() {
+            if let Some(result) = self.forward_to_daemon().await {
+                return result;
+            }
+        }
+
+        match self.args.command {
+            Some(Command::Login { ref server }) => self.login(server).await,
+            Some(Command::Logout { ref server }) => Self::logout(server),
+            Some(Command::InitServer { port, ref log_dir, ref user }) => {
+                Self::init_server(port, log_dir, user);
+                Ok(())
+            }
+            Some(Command::Meta { query }) => self.print_meta(query).await,
+            Some(Command::Test { .. }) => self.build().await.context("Tests failed"),
+            Some(Command::Env { show_sensitive }) => self.print_server_env(show_sensitive).await,
+            Some(Command::ListTargets) => self.list_targets().await,
+            Some(Command::Update { ref toolchain, ref components }) => {
+                self.update_toolchain(toolchain, components).await
+            }
+            Some(Command::ServerStats { period }) => self.server_stats(period).await,
+            Some(Command::ServerLog { follow, lines, filter }) => self.server_log(follow, lines, filter).await,
+            Some(Command::Publish { ref package, dry_run, ref registry, ref token }) => {
+                self.publish(package.as_deref(), dry_run, registry.clone(), token.clone()).await
+            }
+            Some(Command::Vendor) => self.vendor().await,
+            Some(Command::Diagnose) => self.diagnose().await,
+            Some(Command::VerifyInstall { ref require }) => self.verify_install(require).await,
+            Some(Command::Verify { ref checksum_file }) => self.verify_checksums(checksum_file),
+            Some(Command::Diff { ref before, ref after, ref artifact }) => self.diff_artifacts(before, after, artifact),
+            Some(Command::Daemon { action }) => self.daemon(action.unwrap_or(DaemonAction::Start)).await,
+            Some(Command::Completions { shell }) => {
+                Self::print_completions(shell);
+                Ok(())
+            }
+            None if self.args.version_check => self.check_server_version().await,
+            None if self.args.print_tarball_contents => self.print_tarball_contents(),
+            None if self.args.dry_run => self.dry_run().await,
+            None if self.args.run => self.build_and_run().await,
+            None if self.args.watch => self.watch().await,
+            None => self.build().await.context("Build failed"),
+        }
+    }
+
+    fn daemon_socket_path(&self) -> PathBuf {
+        self.tesseract_dir().join("daemon.sock")
+    }
+
+    #[cfg(not(unix))]
+    async fn daemon(&self, _action: DaemonAction) -> Result<()> {
+        Err(anyhow::anyhow!("Daemon mode needs Unix domain sockets, which this platform lacks"))
+    }
+
+    #[cfg(unix)]
+    async fn daemon(&self, action: DaemonAction) -> Result<()> {
+        match action {
+            DaemonAction::Serve => return self.serve_daemon().await,
+            DaemonAction::Start => {
+                if let Ok(DaemonResponse::Pong { pid }) = self.daemon_request(DaemonRequest::Ping).await {
+                    outln!("Daemon already running (pid {})", pid);
+                    return Ok(());
+                }
+                std::fs::create_dir_all(self.tesseract_dir())?;
+                let log_path = self.tesseract_dir().join("daemon.log");
+                let log = std::fs::File::create(&log_path)?;
+                let mut command = std::process::Command::new(std::env::current_exe()?);
+                std::os::unix::process::CommandExt::process_group(&mut command, 0);
+                command
+                    .args(["tess", "daemon", "serve"])
+                    .current_dir(&self.workspace_path)
+                    .stdin(std::process::Stdio::null())
+                    .stdout(log.try_clone()?)
+                    .stderr(log);
+                if self.args.debug {
+                    command.arg("--debug");
+                }
+                // Each forwarded build brings its own TESSERACT_* settings
+                for (key, _) in std::env::vars().filter(|(key, _)| key.starts_with("TESSERACT_")) {
+                    command.env_remove(key);
+                }
+                command.spawn().context("Failed to start the daemon")?;
+
+                for _ in 0..50 {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    if let Ok(DaemonResponse::Pong { pid }) = self.daemon_request(DaemonRequest::Ping).await {
+                        outln!("{} daemon (pid {}), logging to {}", "Started".green().bold(), pid, log_path.display());
+                        return Ok(());
+                    }
+                }
+                Err(anyhow::anyhow!("Daemon did not start; see {}", log_path.display()))
+            }
+            DaemonAction::Stop => match self.daemon_request(DaemonRequest::Stop).await {
+                Ok(_) => {
+                    outln!("{} daemon", "Stopped".green().bold());
+                    Ok(())
+                }
+                Err(_) => {
+                    outln!("No daemon running");
+                    Ok(())
+                }
+            },
+            DaemonAction::Status => {
+                match self.daemon_request(DaemonRequest::Ping).await {
+                    Ok(DaemonResponse::Pong { pid }) => {
+                        outln!("Daemon running (pid {}) on {}", pid, self.daemon_socket_path().display())
+                    }
+                    _ => outln!("No daemon running"),
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends one request to the daemon and reads its answer.
+    #[cfg(unix)]
+    async fn daemon_request(&self, request: DaemonRequest) -> Result<DaemonResponse> {
+        let mut stream = tokio::net::UnixStream::connect(self.daemon_socket_path()).await?;
+        write_framed_json(&mut stream, &request).await?;
+        read_framed_json(&mut stream).await
+    }
+
+    #[cfg(unix)]
+    fn forwardable_to_daemon(&self) -> bool {
+        self.args.command.is_none()
+            && !self.args.watch
+            && !self.args.dry_run
+            // The binary started by --run needs this terminal
+            && !self.args.run
+            && self.args.ssh_tunnel.is_none()
+            && !IN_DAEMON.load(Ordering::Relaxed)
+            && self.daemon_socket_path().exists()
+    }
+
+    /// Hands this invocation to the workspace's daemon, or returns None when no daemon answers.
+    #[cfg(unix)]
+    async fn forward_to_daemon(&self) -> Option<Result<()>> {
+        let mut stream = tokio::net::UnixStream::connect(self.daemon_socket_path()).await.ok()?;
+        let request = DaemonRequest::Run {
+            args: Box::new(self.args.clone()),
+            cwd: self.workspace_path.clone(),
+            env: std::env::vars().collect(),
+        };
+        debug!("Forwarding build to daemon");
+
+        let result = async {
+            write_framed_json(&mut stream, &request).await?;
+            loop {
+                match read_framed_json(&mut stream).await.context("Lost connection to the daemon")? {
+                    DaemonResponse::Output { stream: OutputStream::Stdout, text } => print!("{}", text),
+                    DaemonResponse::Output { stream: OutputStream::Stderr, text } => eprint!("{}", text),
+                    DaemonResponse::Finished { exit_code: Some(code), .. } => return Err(ChildExit { code }.into()),
+                    DaemonResponse::Finished { error: Some(error), .. } => return Err(anyhow::anyhow!(error)),
+                    DaemonResponse::Finished { .. } => return Ok(()),
+                    _ => return Err(anyhow::anyhow!("Unexpected response from the daemon")),
+                }
+            }
+        };
+        Some(result.await)
+    }
+
+    #[cfg(unix)]
+    async fn serve_daemon(&self) -> Result<()> {
+        IN_DAEMON.store(true, Ordering::Relaxed);
+
+        let socket_path = self.daemon_socket_path();
+        std::fs::create_dir_all(self.tesseract_dir())?;
+        let _ = std::fs::remove_file(&socket_path);
+        let listener = tokio::net::UnixListener::bind(&socket_path)
+            .with_context(|| format!("Failed to bind {}", socket_path.display()))?;
+        info!("Daemon listening on {}", socket_path.display());
+
+        // Clients are served concurrently on this task, each build streaming to its own socket
+        let mut clients = futures::stream::FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => clients.push(Self::serve_daemon_client(stream)),
+                    Err(e) => warn!("Failed to accept daemon connection: {}", e),
+                },
+                Some(served) = futures::StreamExt::next(&mut clients) => match served {
+                    Ok(true) => break,
+                    Ok(false) => {}
+                    Err(e) => warn!("Dropping daemon connection: {:#}", e),
+                },
+            }
+        }
+
+        let _ = std::fs::remove_file(&socket_path);
+        info!("Daemon stopped");
+        Ok(())
+    }
+
+    /// Answers one daemon client, returning whether it asked the daemon to stop.
+    #[cfg(unix)]
+    async fn serve_daemon_client(mut stream: tokio::net::UnixStream) -> Result<bool> {
+        // Bounded so a silent client can't hold its connection open forever
+        let request = tokio::time::timeout(DAEMON_HANDSHAKE_TIMEOUT, read_framed_json(&mut stream))
+            .await
+            .context("Timed out waiting for a request")??;
+        let (args, cwd, env) = match request {
+            DaemonRequest::Ping => {
+                write_framed_json(&mut stream, &DaemonResponse::Pong { pid: std::process::id() }).await?;
+                return Ok(false);
+            }
+            DaemonRequest::Stop => {
+                write_framed_json(&mut stream, &DaemonResponse::Stopped).await?;
+                return Ok(true);
+            }
+            DaemonRequest::Run { args, cwd, env } => (args, cwd, env),
+        };
+        info!("Running build in {}", cwd.display());
+
+        let (output, mut pending) = tokio::sync::mpsc::unbounded_channel();
+        let build = FORWARDED_OUTPUT.scope(output, Self::run_forwarded(*args, cwd, env));
+        tokio::pin!(build);
+        // A failed write means the client hung up, which cancels its build
+        let result = loop {
+            tokio::select! {
+                result = &mut build => break result,
+                Some(response) = pending.recv() => write_framed_json(&mut stream, &response).await?,
+            }
+        };
+        while let Ok(response) = pending.try_recv() {
+            write_framed_json(&mut stream, &response).await?;
+        }
+
+        let response = match result {
+            Ok(()) => DaemonResponse::Finished { error: None, exit_code: None },
+            Err(e) => DaemonResponse::Finished {
+                exit_code: e.downcast_ref::<ChildExit>().map(|exit| exit.code),
+                error: Some(format!("{:#}", e)),
+            },
+        };
+        write_framed_json(&mut stream, &response).await?;
+        Ok(false)
+    }
+
+    #[cfg(unix)]
+    async fn run_forwarded(mut args: CliArgs, cwd: PathBuf, env: HashMap<String, String>) -> Result<()> {
+        args.resolve_paths(&cwd);
+        let client = TesseractClient::with_environment(args, cwd, Some(env))?;
+        // Boxed because run() is what reaches serve_daemon in the first place
+        Box::pin(client.run()).await
+    }
+
+    fn is_warning_line(line: &str) -> bool {
+        let line = line.trim_start();
+        (line.starts_with("warning:") || line.starts_with("warning["))
+            && !line.contains(" generated ")
+    }
+
+    /// Whether `line` belongs to a warning already shown --dedup-threshold times.
+    /// Warnings are keyed by their message line, so the same warning at different
+    /// locations counts as a repeat; the rest of its block up to the blank line goes with it.
+    fn is_duplicate_warning(&self, line: &str, in_duplicate: &mut bool) -> bool {
+        let trimmed = line.trim_start();
+        if Self::is_warning_line(line) {
+            let mut seen = self.seen_warnings.lock().unwrap();
+            let count = seen.entry(trimmed.to_string()).or_insert(0);
+            *count += 1;
+            *in_duplicate = *count > self.args.dedup_threshold as usize;
+            if *in_duplicate {
+                self.suppressed_warnings.fetch_add(1, Ordering::Relaxed);
+            }
+        } else if trimmed.starts_with("warning") || trimmed.starts_with("error") {
+            *in_duplicate = false;
+        } else if *in_duplicate && trimmed.is_empty() {
+            *in_duplicate = false;
+            return true;
+        }
+        *in_duplicate
+    }
+
+    fn rustflags(&self) -> Vec<String> {
+        let mut rustflags = Vec::new();
+        if self.args.deny_warnings {
+            rustflags.extend(["-D".to_string(), "warnings".to_string()]);
+        }
+        for remap in &self.args.remap_path_prefix {
+            rustflags.push(format!("--remap-path-prefix={}", remap));
+        }
+        if !self.args.target_features.is_empty() {
+            rustflags.extend(["-C".to_string(), format!("target-feature={}", self.args.target_features.join(","))]);
+        }
+        if let Some(split_debuginfo) = self.args.split_debuginfo {
+            rustflags.extend(["-C".to_string(), format!("split-debuginfo={}", split_debuginfo.as_str())]);
+        }
+        rustflags
+    }
+
+    fn incremental(&self) -> Option<bool> {
+        match self.args.incremental {
+            _ if self.args.no_incremental => Some(false),
+            Some(Incremental::True) => Some(true),
+            Some(Incremental::False) => Some(false),
+            Some(Incremental::Auto) => None,
+            // Matches cargo, which never builds release profiles incrementally
+            None if self.args.release => Some(false),
+            None => None,
+        }
+    }
+
+    fn check_cfg(&self, unit: &BuildUnit) -> Vec<String> {
+        let mut specs = self.args.check_cfg.clone();
+        if self.args.check_cfg_well_known {
+            let values: Vec<_> = unit
+                .declared_features
+                .iter()
+                .map(|feature| format!("{:?}", feature))
+                .collect();
+            specs.push(format!("cfg(feature, values({}))", values.join(", ")));
+            specs.push("cfg(docsrs)".to_string());
+        }
+        specs
+    }
+
+    async fn resume_artifacts(&self, server: &str, build_id: Uuid, chunks: &HashMap<PathBuf, Vec<u8>>) -> Result<TcpStream> {
+        let mut stream = self.connect(server).await?;
+        let offsets = chunks.iter().map(|(path, data)| (path.clone(), data.len() as u64)).collect();
+        Self::send_request(&mut stream, &BuildRequest::ResumeArtifact { build_id, offsets }).await?;
+        Ok(stream)
+    }
+
+    async fn reconnect_build(&self, server: &str, build_id: Uuid) -> Result<TcpStream> {
+        let mut stream = self.connect(server).await?;
+        Self::send_request(&mut stream, &BuildRequest::ReconnectBuild { build_id }).await?;
+        Ok(stream)
+    }
+
+    async fn handle_build_stream(
+        &self,
+        mut stream: TcpStream,
+        server: &str,
+        unit: &BuildUnit,
+        build_id: Uuid,
+        source_sha256: &[u8],
+    ) -> Result<()> {
+        // Only hold the lock while touching the map so concurrent packages don't serialize
+        let package_bar = self
+            .progress
+            .lock()
+            .await
+            .entry(unit.package_name.clone())
+            .or_insert_with(|| BuildProgress {
+                package_bar: self.create_progress_bar(&format!("Building {}", unit.package_name)),
+                build_output: Vec::new(),
+            })
+            .package_bar
+            .clone();
+
+        let started = std::time::Instant::now();
+        let eta_ticker = if self.args.eta {
+            self.start_eta(&unit.package_name, &package_bar)
+        } else {
+            None
+        };
+
+        let mut log_file = match self.args.log_file {
+            Some(ref path) => Some(
+                tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .with_context(|| format!("Failed to open log file {}", path.display()))?,
+            ),
+            None => None,
+        };
+        let mut suppressed_output = Vec::new();
+        let mut short_diagnostics = ShortDiagnostics::default();
+        let mut ci_formatter = self.args.color_scheme.unwrap_or_else(|| ColorScheme::detect(|var| self.env_var(var))).formatter();
+        let section_name = format!("Building {}", unit.package_name);
+        if !self.args.hide_output {
+            if let Some(line) = ci_formatter.section_start(&section_name) {
+                outln!("{}", line);
+            }
+        }
+        let mut resumable = false;
+        let mut reconnect_attempts = 0;
+        let mut unused_deps_found = false;
+        let mut chunks: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+        let mut download_retries = 0;
+        let mut in_duplicate_warning = false;
+
+        loop {
+            let response = match Self::read_response(&mut stream).await {
+                Ok(response) => response,
+                Err(e) if self.args.resumable_download
+                    && !chunks.is_empty()
+                    && download_retries < self.args.download_retries
+                    && e.root_cause().is::<std::io::Error>() =>
+                {
+                    download_retries += 1;
+                    let delay = Duration::from_secs(1 << (download_retries - 1).min(6));
+                    warn!(
+                        "Lost connection while downloading artifacts for {} ({:#}), resuming in {:?} ({}/{})",
+                        unit.package_name, e, delay, download_retries, self.args.download_retries
+                    );
+                    package_bar.set_message(format!("Building {} - resuming download", unit.package_name));
+                    tokio::time::sleep(delay).await;
+                    match self.resume_artifacts(server, build_id, &chunks).await {
+                        Ok(new_stream) => stream = new_stream,
+                        Err(e) => warn!("Resuming the download failed: {:#}", e),
+                    }
+                    continue;
+                }
+                Err(e) if resumable
+                    && reconnect_attempts < self.args.reconnect_attempts
+                    && e.root_cause().is::<std::io::Error>() =>
+                {
+                    reconnect_attempts += 1;
+                    warn!(
+                        "Lost connection during build of {} ({:#}), reconnecting ({}/{})",
+                        unit.package_name, e, reconnect_attempts, self.args.reconnect_attempts
+                    );
+                    package_bar.set_message(format!("Building {} - reconnecting", unit.package_name));
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    match self.reconnect_build(server, build_id).await {
+                        Ok(new_stream) => stream = new_stream,
+                        Err(e) => warn!("Reconnect failed: {:#}", e),
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    Self::print_held_output(&mut short_diagnostics, &suppressed_output);
+                    return Err(e);
+                }
+            };
+
+            match response {
+                BuildResponse::BuildAccepted { build_id: accepted_id } => {
+                    resumable = accepted_id == build_id;
+                    info!("Server accepted build {} for {}", accepted_id, unit.package_name);
+                }
+                BuildResponse::BuildOutput { output, is_error, .. } => {
+                    if Self::is_warning_line(&output) {
+                        self.warning_count.fetch_add(1, Ordering::Relaxed);
+                    }
+
+                    if let Some(ref mut log_file) = log_file {
+                        log_file
+                            .write_all(format!("[{}] {}\n", unit.package_name, output).as_bytes())
+                            .await?;
+                    }
+                    if self.args.deduplicate_warnings && self.is_duplicate_warning(&output, &mut in_duplicate_warning) {
+                        continue;
+                    }
+
+                    let lines = match self.args.error_format {
+                        ErrorFormat::Human => vec![output],
+                        ErrorFormat::Short => short_diagnostics.format_line(&output),
+                    };
+                    for output in lines {
+                        let output = ci_formatter.format_output(&output, is_error);
+                        if self.args.hide_output {
+                            package_bar.set_message(format!("Building {} - {}", unit.package_name, output.trim()));
+                            suppressed_output.push(output.clone());
+                        } else {
+                            outln!("{}", output);
+                        }
+                        if let Some(build_progress) = self.progress.lock().await.get_mut(&unit.package_name) {
+                            build_progress.build_output.push(output);
+                        }
+                    }
+                }
+                BuildResponse::Progress { unit_name, stage, message, percent } => {
+                    if let (Some(percent), None) = (percent, &eta_ticker) {
+                        if package_bar.position() == 0 {
+                            package_bar.set_style(
+                                ProgressStyle::default_bar()
+                                    .template("{spinner:.green} [{bar:30.cyan/blue}] {pos:>3}% {msg}")
+                                    .unwrap()
+                                    .tick_chars(self.display.spinner.tick_chars())
+                                    .progress_chars("=> "),
+                            );
+                        }
+                        package_bar.set_position(percent.min(100).into());
+                    }
+                    package_bar.set_message(format!("Building {} - {} {}", unit_name, stage, message));
+                }
+                BuildResponse::ArtifactChunk { path, offset, data } => {
+                    let buffer = chunks.entry(path.clone()).or_default();
+                    // A resumed transfer may repeat bytes we already have, but must not skip any
+                    if offset > buffer.len() as u64 {
+                        return Err(anyhow::anyhow!(
+                            "Artifact {} chunk starts at byte {} but only {} bytes were received",
+                            path.display(),
+                            offset,
+                            buffer.len()
+                        ));
+                    }
+                    buffer.truncate(offset as usize);
+                    buffer.extend_from_slice(&data);
+                    package_bar.set_message(format!(
+                        "Building {} - Downloading {} ({} KB)",
+                        unit.package_name,
+                        path.display(),
+                        buffer.len() / 1024
+                    ));
+                }
+                BuildResponse::BuildComplete { unit_name, mut artifacts, toolchain } => {
+                    for artifact in &mut artifacts {
+                        if let ArtifactData::Chunked = artifact.data {
+                            let data = chunks.remove(&artifact.path).with_context(|| {
+                                format!("Server sent no chunks for artifact {}", artifact.path.display())
+                            })?;
+                            artifact.data = ArtifactData::Full(data);
+                        }
+                    }
+                    let mut transfer_sizes = String::new();
+                    if self.args.compress_artifacts != CompressionAlgorithm::None {
+                        let (mut compressed_size, mut size) = (0, 0);
+                        for artifact in &mut artifacts {
+                            if let ArtifactData::Full(ref mut data) = artifact.data {
+                                compressed_size += data.len();
+                                *data = self.args.compress_artifacts.decompress(std::mem::take(data))
+                                    .with_context(|| format!("Failed to decompress artifact {}", artifact.path.display()))?;
+                                size += data.len();
+                            }
+                        }
+                        transfer_sizes = format!(" ({} KB received, {} KB uncompressed)", compressed_size / 1024, size / 1024);
+                    }
+                    if let Some(line) = short_diagnostics.flush() {
+                        if !self.args.hide_output {
+                            outln!("{}", line);
+                        }
+                    }
+                    package_bar.set_message(format!("Building {} - Saving artifacts{}", unit_name, transfer_sizes));
+                    if self.args.eta {
+                        if let Err(e) = self.record_build_duration(&unit.package_name, started.elapsed()).await {
+                            warn!("Failed to record build time for {}: {:#}", unit.package_name, e);
+                        }
+                    }
+
+                    let local_paths: Vec<_> = artifacts.iter().map(|a| self.local_artifact_path(unit, &a.path)).collect();
+                    self.save_artifacts(unit, artifacts).await?;
+                    if let Err(e) = self.cache_artifacts(&local_paths) {
+                        warn!("Failed to add artifacts to the local cache: {:#}", e);
+                    }
+                    self.built_artifacts.lock().await.push((
+                        unit.package_name.clone(),
+                        source_sha256.iter().map(|b| format!("{:02x}", b)).collect(),
+                        local_paths.clone(),
+                    ));
+
+                    if self.args.trace_artifact_provenance {
+                        self.write_provenance(&local_paths, source_sha256, toolchain).await
+                            .context("Failed to write artifact provenance")?;
+                    }
+
+                    if let Some(ref destination) = self.args.upload_artifacts_to_s3 {
+                        package_bar.set_message(format!("Building {} - Uploading artifacts", unit_name));
+                        self.upload_artifacts_to_s3(destination, &local_paths).await
+                            .context("Failed to upload artifacts to S3")?;
+                    }
+
+                    if !self.args.hide_output {
+                        if let Some(line) = ci_formatter.section_end(&section_name) {
+                            outln!("{}", line);
+                        }
+                    }
+                    if unused_deps_found && self.args.error_on_unused_deps {
+                        package_bar.finish_with_message(
+                            format!("{} has unused dependencies", unit_name).red().to_string(),
+                        );
+                        return Err(anyhow::anyhow!(
+                            "Unused dependencies found (--error-on-unused-deps)"
+                        ));
+                    }
+                    package_bar.finish_with_message(
+                        format!("{} built successfully", unit_name).green().to_string(),
+                    );
+                    return Ok(());
+                }
+                BuildResponse::TimingReport { unit_name, html } => {
+                    let report_path = self.timing_report_path();
+                    info!("Writing timing report for {} to {}", unit_name, report_path.display());
+                    Self::write_artifact_safely(&report_path, html.as_bytes()).await
+                        .with_context(|| format!("Failed to write timing report to {}", report_path.display()))?;
+                }
+                BuildResponse::UnusedDeps { packages } => {
+                    for (package, deps) in packages.iter().filter(|(_, deps)| !deps.is_empty()) {
+                        unused_deps_found = true;
+                        errln!(
+                            "{} {} has unused dependencies: {}",
+                            "warning:".yellow().bold(),
+                            package,
+                            deps.join(", ")
+                        );
+                    }
+                }
+                BuildResponse::RateLimited { retry_after_secs } => {
+                    package_bar.finish_and_clear();
+                    Self::print_held_output(&mut short_diagnostics, &suppressed_output);
+                    return Err(RateLimited { retry_after_secs }.into());
+                }
+                BuildResponse::MetadataChecksumMismatch { expected, actual } => {
+                    return Err(MetadataChecksumMismatch { expected, actual }.into());
+                }
+                BuildResponse::UnknownDictionary { dictionary_id } => {
+                    return Err(UnknownDictionary { dictionary_id }.into());
+                }
+                BuildResponse::FeatureUnavailable { feature, min_cargo_version } => {
+                    warn!(
+                        "Server cargo does not support {} (needs {}); building without it",
+                        feature, min_cargo_version
+                    );
+                }
+                BuildResponse::MemoryWarning { used_bytes, limit_bytes } => {
+                    let mb = |bytes: u64| bytes / (1024 * 1024);
+                    warn!(
+                        "Build of {} is using {} MB of {} MB server memory",
+                        unit.package_name,
+                        mb(used_bytes),
+                        mb(limit_bytes)
+                    );
+                    package_bar.set_message(
+                        format!(
+                            "Building {} - high memory use ({} / {} MB)",
+                            unit.package_name,
+                            mb(used_bytes),
+                            mb(limit_bytes)
+                        )
+                        .yellow()
+                        .to_string(),
+                    );
+                }
+                BuildResponse::ToolchainFeatureUnavailable { unit_name, feature, reason } => {
+                    package_bar.finish_with_message(
+                        format!("{} build failed", unit_name).red().to_string(),
+                    );
+                    Self::print_held_output(&mut short_diagnostics, &suppressed_output);
+                    return Err(anyhow::anyhow!(
+                        "The server's toolchain does not support {}: {}",
+                        feature,
+                        reason
+                    ));
+                }
+                BuildResponse::DiskQuotaExceeded { available_bytes, required_bytes } => {
+                    package_bar.finish_with_message(
+                        format!("{} build failed", unit.package_name).red().to_string(),
+                    );
+                    Self::print_held_output(&mut short_diagnostics, &suppressed_output);
+                    let mb = |bytes: u64| bytes / (1024 * 1024);
+                    return Err(anyhow::anyhow!(
+                        "Server disk space exhausted ({}MB available, {}MB needed); ask the server administrator to free space",
+                        mb(available_bytes),
+                        mb(required_bytes)
+                    ));
+                }
+                BuildResponse::BuildError { unit_name, error, error_kind, workspace_path } => {
+                    package_bar.finish_with_message(
+                        format!("{} build failed", unit_name).red().to_string(),
+                    );
+                    Self::print_held_output(&mut short_diagnostics, &suppressed_output);
+                    if !self.args.hide_output {
+                        if let Some(line) = ci_formatter.section_end(&section_name) {
+                            outln!("{}", line);
+                        }
+                    }
+                    errln!("{}", format!("hint: {}", error_kind.hint()).yellow());
+                    if let Some(workspace_path) = workspace_path {
+                        errln!("Server workspace preserved at {} for debugging.", workspace_path.bold());
+                    }
+                    return Err(anyhow::anyhow!("Build failed ({}): {}", error_kind, error));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// On a failed build, shows what --hide-output held back and any unfinished short diagnostic
+    fn print_held_output(short_diagnostics: &mut ShortDiagnostics, suppressed_output: &[String]) {
+        for line in suppressed_output.iter().cloned().chain(short_diagnostics.flush()) {
+            errln!("{}", line);
+        }
+    }
+
+    /// Hash of every manifest and lockfile plus the cargo that reads them, so switching
+    /// toolchains never reuses metadata resolved by another cargo
+    fn metadata_cache_key(&self) -> Result<String> {
+        let mut manifests: Vec<_> = WalkDir::new(&self.workspace_path)
+            .into_iter()
+            .filter_entry(|e| e.file_name() != "target" && e.file_name() != ".git")
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name() == "Cargo.toml" || e.file_name() == "Cargo.lock")
+            .map(|e| e.into_path())
+            .collect();
+        manifests.sort();
+
+        let mut hasher = Sha256::new();
+        hasher.update(format!(
+            "cargo_path={:?}\nuse_rustup_cargo={:?}\n",
+            self.args.cargo_path, self.args.use_rustup_cargo
+        ));
+        for manifest in manifests {
+            hasher.update(manifest.to_string_lossy().as_bytes());
+            hasher.update(std::fs::read(&manifest)?);
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    fn read_lockfile(&self) -> Result<Vec<u8>> {
+        let path = self.workspace_metadata()?.workspace_root.join("Cargo.lock");
+        std::fs::read(&path)
+            .with_context(|| format!("Failed to read {}; run `cargo generate-lockfile` first", path))
+    }
+
+    fn forwarded_env(&self) -> Result<HashMap<String, String>> {
+        let mut env = HashMap::new();
+        if self.args.auto_build_metadata {
+            env.extend(self.auto_build_metadata.get_or_init(|| self.detect_build_metadata()).iter().cloned());
+        }
+        if let Some(ref path) = self.args.inject_env_from_file {
+            for entry in dotenvy::from_path_iter(path).with_context(|| format!("Failed to read {}", path.display()))? {
+                let (key, value) = entry.with_context(|| format!("Failed to parse {}", path.display()))?;
+                env.insert(key, value);
+            }
+        }
+        env.extend(self.args.build_metadata.iter().cloned());
+        Ok(env)
+    }
+
+    fn detect_build_metadata(&self) -> Vec<(String, String)> {
+        let git = |args: &[&str]| -> Option<String> {
+            let output = self.command("git")
+                .args(args)
+                .output()
+                .ok()?;
+            output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        };
+
+        let mut metadata = Vec::new();
+        match git(&["rev-parse", "HEAD"]) {
+            Some(commit) => metadata.push(("GIT_COMMIT".to_string(), commit)),
+            None => warn!("Could not determine the git commit for GIT_COMMIT"),
+        }
+        if let Some(branch) = git(&["rev-parse", "--abbrev-ref", "HEAD"]) {
+            metadata.push(("GIT_BRANCH".to_string(), branch));
+        }
+        metadata.push((
+            "BUILD_TIMESTAMP".to_string(),
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        ));
+        metadata.push(("CARGO_TESSERACT_VERSION".to_string(), env!("CARGO_PKG_VERSION").to_string()));
+        metadata
+    }
+
+    fn lockfile_sha256(&self) -> Result<[u8; 32]> {
+        Ok(Sha256::digest(self.read_lockfile()?).into())
+    }
+
+    fn pinned_deps(&self) -> Result<HashMap<String, String>> {
+        if !self.args.pin_deps {
+            return Ok(HashMap::new());
+        }
+
+        #[derive(Deserialize)]
+        struct Lockfile {
+            #[serde(default)]
+            package: Vec<LockedPackage>,
+        }
+        #[derive(Deserialize)]
+        struct LockedPackage {
+            name: String,
+            version: String,
+            source: Option<String>,
+        }
+
+        let lockfile: Lockfile = toml::from_str(std::str::from_utf8(&self.read_lockfile()?)?)
+            .context("Failed to parse Cargo.lock")?;
+        let mut pinned = HashMap::new();
+        let mut ambiguous = HashSet::new();
+        for package in lockfile.package {
+            if package.source.as_deref() != Some("registry+https://github.com/rust-lang/crates.io-index") {
+                continue;
+            }
+            // A single patch entry can't pin several versions of the same crate
+            if pinned.insert(package.name.clone(), package.version).is_some() {
+                ambiguous.insert(package.name);
+            }
+        }
+        for name in ambiguous {
+            debug!("Not pinning {}: Cargo.lock has several versions", name);
+            pinned.remove(&name);
+        }
+        Ok(pinned)
+    }
+
+    /// Lockfile hash of the last vendor archive uploaded to each server
+    fn vendor_record_path(&self) -> PathBuf {
+        self.tesseract_dir().join("vendored-lockfiles.json")
+    }
+
+    fn vendored_lockfile(&self) -> Result<Option<[u8; 32]>> {
+        let Ok(content) = std::fs::read(self.vendor_record_path()) else {
+            return Ok(None);
+        };
+        let records: HashMap<String, [u8; 32]> = serde_json::from_slice(&content).unwrap_or_default();
+        let Some(vendored) = records.get(self.server_addr()?) else {
+            return Ok(None);
+        };
+        if *vendored != self.lockfile_sha256()? {
+            warn!("Cargo.lock changed since the last `cargo tess vendor`; building online");
+            return Ok(None);
+        }
+        Ok(Some(*vendored))
+    }
+
+    async fn vendor(&self) -> Result<()> {
+        let lockfile_sha256 = self.lockfile_sha256()?;
+        let staging = tempfile::tempdir()?;
+        let vendor_dir = staging.path().join("vendor");
+
+        info!("Running cargo vendor");
+        let output = self.command("cargo")
+            .arg("vendor")
+            .arg(&vendor_dir)
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .context("Failed to run cargo vendor")?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!("cargo vendor failed with {}", output.status));
+        }
+        // cargo vendor prints the source replacement config; point it at the extracted copy
+        let config = String::from_utf8(output.stdout)?
+            .replace(&vendor_dir.display().to_string(), "vendor");
+
+        let mut builder = Builder::new(Vec::new());
+        builder.append_dir_all("vendor", &vendor_dir)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(config.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, ".cargo/config.toml", config.as_bytes())?;
+        let tarball = Self::gzip(&builder.into_inner()?)?;
+
+        info!("Uploading {} byte vendor archive", tarball.len());
+        let mut stream = self.connect(self.server_addr()?).await?;
+        Self::send_request(&mut stream, &BuildRequest::VendorArchive {
+            lockfile_sha256,
+            tarball,
+            auth_token: self.auth_token(self.server_addr()?),
+        }).await?;
+
+        let vendor_dir = match Self::read_response(&mut stream).await? {
+            BuildResponse::VendorStored { vendor_dir } => vendor_dir,
+            BuildResponse::BuildError { error, .. } => {
+                return Err(anyhow::anyhow!("Vendor upload failed: {}", error));
+            }
+            other => return Err(anyhow::anyhow!("Unexpected response to vendor: {:?}", other)),
+        };
+
+        let record_path = self.vendor_record_path();
+        let mut records: HashMap<String, [u8; 32]> = std::fs::read(&record_path)
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default();
+        records.insert(self.server_addr()?.to_string(), lockfile_sha256);
+        std::fs::create_dir_all(self.tesseract_dir())?;
+        std::fs::write(&record_path, serde_json::to_vec(&records)?)?;
+
+        outln!("{} dependencies to {}", "Vendored".green().bold(), vendor_dir);
+        Ok(())
+    }
+
+    fn metadata_cache_path(&self, resolve_dependencies: bool) -> PathBuf {
+        self.tesseract_dir()
+            .join(if resolve_dependencies { "resolved-metadata-cache.json" } else { "metadata-cache.json" })
+    }
+
+    /// Cargo binary for `cargo metadata`; `None` uses the one on PATH
+    fn metadata_cargo(&self) -> Result<Option<PathBuf>> {
+        if let Some(ref path) = self.args.cargo_path {
+            return Ok(Some(path.clone()));
+        }
+        let Some(ref toolchain) = self.args.use_rustup_cargo else {
+            return Ok(None);
+        };
+        let output = self.command("rustup")
+            .args(["which", "cargo", "--toolchain", toolchain])
+            .output()
+            .context("Failed to run rustup for --use-rustup-cargo")?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Could not find cargo for toolchain {}: {}",
+                toolchain,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(Some(PathBuf::from(String::from_utf8(output.stdout)?.trim())))
+    }
+
+    fn workspace_metadata(&self) -> Result<Metadata> {
+        self.cached_metadata(false)
+    }
+
+    /// Workspace metadata with the full dependency graph resolved
+    fn resolved_metadata(&self) -> Result<Metadata> {
+        if let Some(metadata) = self.resolved_metadata.get() {
+            return Ok(metadata.clone());
+        }
+        let metadata = self.cached_metadata(true)?;
+        // Watch mode keeps the client across rebuilds, so it relies on the keyed cache instead
+        if !self.args.watch {
+            let _ = self.resolved_metadata.set(metadata.clone());
+        }
+        Ok(metadata)
+    }
+
+    fn cached_metadata(&self, resolve_dependencies: bool) -> Result<Metadata> {
+        let mut command = self.metadata_command()?;
+        if !resolve_dependencies {
+            command.no_deps();
+        }
+        let what = if resolve_dependencies { "dependency graph" } else { "workspace metadata" };
+
+        // Caching only pays off across watch-mode rebuilds and daemon-served builds
+        let in_daemon = IN_DAEMON.load(Ordering::Relaxed);
+        if !self.args.watch && !in_daemon {
+            return Ok(command.exec()?);
+        }
+
+        let cache_key = self.metadata_cache_key()?;
+        if in_daemon {
+            let cache = DAEMON_METADATA.get_or_init(Default::default);
+            if let Some(metadata) = cache.lock().unwrap().get(&(cache_key.clone(), resolve_dependencies)) {
+                info!("Using the daemon's cached {}", what);
+                return Ok(metadata.clone());
+            }
+            let metadata = command.exec()?;
+            cache.lock().unwrap().insert((cache_key, resolve_dependencies), metadata.clone());
+            return Ok(metadata);
+        }
+        let cache_path = self.metadata_cache_path(resolve_dependencies);
+        if let Ok(content) = std::fs::read(&cache_path) {
+            if let Ok(cached) = serde_json::from_slice::<CachedMetadata>(&content) {
+                if cached.cache_key == cache_key {
+                    info!("Using cached {}", what);
+                    return Ok(cached.metadata);
+                }
+            }
+        }
+
+        let metadata = command.exec()?;
+        std::fs::create_dir_all(self.tesseract_dir())?;
+        std::fs::write(
+            &cache_path,
+            serde_json::to_vec(&CachedMetadata {
+                cache_key,
+                metadata: metadata.clone(),
+            })?,
+        )?;
+        Ok(metadata)
+    }
+
+    /// Reports external crates that the resolver pulled in at more than one version.
+    fn check_version_mismatches(&self) -> Result<()> {
+        let policy = self.args.package_version_mismatch;
+        if policy == VersionMismatchPolicy::Ignore {
+            return Ok(());
+        }
+
+        let metadata = self.resolved_metadata().context("Failed to resolve dependencies")?;
+
+        let mut versions: BTreeMap<&str, Vec<&cargo_metadata::Package>> = BTreeMap::new();
+        for package in metadata.packages.iter().filter(|p| !metadata.workspace_members.contains(&p.id)) {
+            versions.entry(package.name.as_str()).or_default().push(package);
+        }
+        versions.retain(|_, packages| packages.len() > 1);
+        if versions.is_empty() {
+            return Ok(());
+        }
+
+        let names: HashMap<_, _> = metadata
+            .packages
+            .iter()
+            .map(|p| (&p.id, format!("{} {}", p.name, p.version)))
+            .collect();
+        let mut dependents: HashMap<&cargo_metadata::PackageId, Vec<&str>> = HashMap::new();
+        for node in metadata.resolve.iter().flat_map(|resolve| &resolve.nodes) {
+            for dependency in &node.dependencies {
+                dependents.entry(dependency).or_default().push(&names[&node.id]);
+            }
+        }
+
+        let rows: Vec<(String, String)> = versions
+            .values()
+            .flatten()
+            .map(|package| {
+                let mut by = dependents.get(&package.id).cloned().unwrap_or_default();
+                by.sort_unstable();
+                (names[&package.id].clone(), by.join(", "))
+            })
+            .collect();
+        let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0).max("CRATE".len());
+        let heading = if policy == VersionMismatchPolicy::Strict { "error".red().bold() } else { "warning".yellow().bold() };
+        errln!("{}: {} crates are resolved at more than one version", heading, versions.len());
+        errln!("{:<width$}  {}", "CRATE".bold(), "REQUIRED BY".bold(), width = width);
+        for (name, by) in &rows {
+            errln!("{:<width$}  {}", name, by, width = width);
+        }
+
+        if policy == VersionMismatchPolicy::Strict {
+            return Err(anyhow::anyhow!(
+                "Duplicate dependency versions: {}",
+                versions.keys().copied().collect::<Vec<_>>().join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn discover_build_units(&self) -> Result<Vec<BuildUnit>> {
+        self.discover_build_units_filtered(&UnitFilter {
+            bins: self.args.bin.clone().into_iter().collect(),
+            ignored_kinds: self.args.ignore_kind.clone(),
+            ..UnitFilter::default()
+        })
+    }
+
+    pub fn discover_build_units_filtered(&self, filter: &UnitFilter) -> Result<Vec<BuildUnit>> {
+        let metadata = self.workspace_metadata()?;
+        self.check_dependency_cycles(&metadata.packages)?;
+
+        let include_tests = self.args.include_tests || self.cargo_command() == CargoCommand::Test;
+        let mut default_kinds = vec![TargetKind::Lib, TargetKind::Bin];
+        if include_tests {
+            default_kinds.extend([TargetKind::Test, TargetKind::Example]);
+        }
+        let kinds = if filter.kinds.is_empty() { &default_kinds } else { &filter.kinds };
+        if kinds.iter().all(|kind| filter.ignored_kinds.contains(kind)) {
+            return Err(anyhow::anyhow!("Every target kind is excluded by --ignore-kind; nothing to build"));
+        }
+
+        let mut units = Vec::new();
+        let proc_macro_packages = self.proc_macro_packages(&metadata);
+        // Dev-dependencies may form cycles and don't affect build order
+        let build_order_deps: HashMap<String, Vec<String>> = metadata
+            .packages
+            .iter()
+            .map(|package| {
+                let dependencies = package
+                    .dependencies
+                    .iter()
+                    .filter(|d| d.kind != cargo_metadata::DependencyKind::Development)
+                    .map(|d| d.name.clone())
+                    .collect();
+                (package.name.clone(), dependencies)
+            })
+            .collect();
+
+        for package in metadata.packages {
+            if !filter.accepts_package(&package.name) {
+                continue;
+            }
+
+            // `publish = false` shows up as an empty registry list
+            let publishable = package.publish.as_ref().is_none_or(|registries| !registries.is_empty());
+            if (self.args.skip_unpublishable && !publishable) || (self.args.only_unpublishable && publishable) {
+                info!("Skipping package {}", package.name);
+                continue;
+            }
+
+            let manifest_dir = Path::new(&package.manifest_path).parent().unwrap();
+            info!("Processing package {} at {}", package.name, manifest_dir.display());
+
+            let mut source_files = Vec::new();
+            source_files.push(package.manifest_path.into());
+
+            let workspace_manifest = Path::new(&metadata.workspace_root).join("Cargo.toml");
+            if workspace_manifest.exists() {
+                source_files.push(workspace_manifest);
+            }
+
+            for target in &package.targets {
+                if filter.accepts_target(target, &default_kinds) {
+                    let src_path = Path::new(&target.src_path);
+                    let src_dir = src_path.parent().unwrap();
+                    
+                    info!("Scanning directory: {}", src_dir.display());
+                    
+                    for entry in WalkDir::new(src_dir) {
+                        match entry {
+                            Ok(entry) => {
+                                if entry.path().extension().is_some_and(|ext| ext == "rs") {
+                                    info!("Found source file: {}", entry.path().display());
+                                    source_files.push(entry.path().to_path_buf());
+                                }
+                            }
+                            Err(e) => warn!("Error walking directory: {}", e),
+                        }
+                    }
+                }
+            }
+
+            let unit = BuildUnit {
+                package_name: package.name.clone(),
+                version: package.version.to_string(),
+                declared_features: {
+                    let mut features: Vec<_> = package.features.keys().cloned().collect();
+                    features.sort();
+                    features
+                },
+                dependencies: package
+                    .dependencies
+                    .iter()
+                    .map(|d| d.name.clone())
+                    .collect(),
+                proc_macro_deps: package
+                    .dependencies
+                    .iter()
+                    .filter(|d| d.kind == cargo_metadata::DependencyKind::Build || proc_macro_packages.contains(&d.name))
+                    .map(|d| d.name.clone())
+                    .collect(),
+                is_proc_macro: proc_macro_packages.contains(&package.name),
+                source_files,
+                artifacts: package
+                    .targets
+                    .iter()
+                    .filter(|t| filter.accepts_target(t, &default_kinds) && t.kind.iter().any(|k| k == "lib" || k == "bin"))
+                    .map(|t| PathBuf::from(&t.name))
+                    .collect(),
+            };
+
+            units.push(unit);
+        }
+
+        Self::topological_order(units, &build_order_deps)
+    }
+
+    /// Names of proc-macro packages in the resolved dependency graph, external crates included
+    fn proc_macro_packages(&self, workspace: &Metadata) -> HashSet<String> {
+        let proc_macros = |packages: &[cargo_metadata::Package]| {
+            packages
+                .iter()
+                .filter(|package| package.targets.iter().any(|t| t.kind.iter().any(|k| k == "proc-macro")))
+                .map(|package| package.name.clone())
+                .collect()
+        };
+        match self.resolved_metadata() {
+            Ok(metadata) => proc_macros(&metadata.packages),
+            Err(e) => {
+                warn!("Could not resolve dependencies, only detecting workspace proc macros: {:#}", e);
+                proc_macros(&workspace.packages)
+            }
+        }
+    }
+
+    /// Orders units so each comes after the workspace packages it depends on
+    fn topological_order(units: Vec<BuildUnit>, dependencies: &HashMap<String, Vec<String>>) -> Result<Vec<BuildUnit>> {
+        let mut graph = petgraph::graph::DiGraph::<usize, ()>::new();
+        let nodes: HashMap<&str, _> = units
+            .iter()
+            .enumerate()
+            .map(|(index, unit)| (unit.package_name.as_str(), graph.add_node(index)))
+            .collect();
+        for unit in &units {
+            for dependency in dependencies.get(&unit.package_name).into_iter().flatten() {
+                if let Some(&from) = nodes.get(dependency.as_str()) {
+                    graph.add_edge(from, nodes[unit.package_name.as_str()], ());
+                }
+            }
+        }
+        let order = petgraph::algo::toposort(&graph, None).map_err(|cycle| {
+            anyhow::anyhow!("Dependency cycle involving {}", units[graph[cycle.node_id()]].package_name)
+        })?;
+
+        let mut units: Vec<_> = units.into_iter().map(Some).collect();
+        Ok(order.into_iter().filter_map(|node| units[graph[node]].take()).collect())
+    }
+
+    fn check_dependency_cycles(&self, packages: &[cargo_metadata::Package]) -> Result<()> {
+        // Dev-dependency cycles are allowed by cargo
+        let mut graph = petgraph::graph::DiGraph::<&cargo_metadata::Package, f32>::new();
+        let nodes: HashMap<&str, _> = packages.iter().map(|p| (p.name.as_str(), graph.add_node(p))).collect();
+        for package in packages {
+            for dependency in &package.dependencies {
+                if dependency.kind == cargo_metadata::DependencyKind::Development {
+                    continue;
+                }
+                if let Some(&to) = nodes.get(dependency.name.as_str()) {
+                    graph.add_edge(nodes[package.name.as_str()], to, -1.0);
+                }
+            }
+        }
+        if !petgraph::algo::is_cyclic_directed(&graph) {
+            return Ok(());
+        }
+        if !self.args.report_cycle_details {
+            return Err(anyhow::anyhow!(
+                "Dependency cycle detected between workspace packages (use --report-cycle-details to show it)"
+            ));
+        }
+
+        // With every edge weighted -1, any cycle is a negative cycle
+        let cycle = graph
+            .node_indices()
+            .find_map(|start| petgraph::algo::find_negative_cycle(&graph, start))
+            .ok_or_else(|| anyhow::anyhow!("Dependency cycle detected between workspace packages"))?;
+
+        let names: Vec<_> = cycle.iter().chain(cycle.first()).map(|&node| graph[node].name.as_str()).collect();
+        let mut report = format!("Dependency cycle detected: {}", names.join(" → "));
+        for pair in cycle.iter().chain(cycle.first()).collect::<Vec<_>>().windows(2) {
+            let (from, to) = (graph[*pair[0]], graph[*pair[1]]);
+            let manifest = std::fs::read_to_string(&from.manifest_path).unwrap_or_default();
+            let declaration = manifest.lines().enumerate().find(|(_, line)| {
+                let line = line.trim_start();
+                line.strip_prefix(to.name.as_str())
+                    .is_some_and(|rest| rest.trim_start().starts_with(['=', '.']))
+                    || line.trim_end().ends_with(&format!("dependencies.{}]", to.name))
+            });
+            match declaration {
+                Some((index, line)) => report.push_str(&format!(
+                    "\n  {}:{}: {}",
+                    from.manifest_path,
+                    index + 1,
+                    line.trim()
+                )),
+                None => report.push_str(&format!("\n  {}: depends on {}", from.manifest_path, to.name)),
+            }
+        }
+        Err(anyhow::anyhow!(report))
+    }
+
+    async fn build_unit(&self, unit: BuildUnit, server: &str, attempt: u32) -> Result<()> {
+        info!("Building package {} on {} (attempt {})", unit.package_name, server, attempt);
+
+        match self.send_build_unit(&unit, server, false, false).await {
+            Err(e) if e.chain().any(|cause| cause.is::<MetadataChecksumMismatch>()) => {
+                warn!("Stale workspace metadata for {} ({:#}), retrying with a fresh cache", unit.package_name, e);
+                let _ = std::fs::remove_file(self.metadata_cache_path(false));
+                self.send_build_unit(&unit, server, true, false).await
+            }
+            Err(e) if e.chain().any(|cause| cause.is::<UnknownDictionary>()) => {
+                info!("{:#}; sending it with the build of {}", e, unit.package_name);
+                self.send_build_unit(&unit, server, false, true).await
+            }
+            result => result,
+        }
+    }
+
+    /// Checksum the server compares against the uploaded manifests
+    fn metadata_checksum(unit: &BuildUnit) -> Result<[u8; 32]> {
+        let workspace_root = Self::tarball_workspace_root(unit)?;
+        let mut manifests: Vec<_> = WalkDir::new(&workspace_root)
+            .into_iter()
+            .filter_entry(|e| e.file_name() != "target" && e.file_name() != ".git")
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name() == "Cargo.toml")
+            .map(|e| e.into_path())
+            .collect();
+        manifests.sort();
+
+        let mut hasher = Sha256::new();
+        for manifest in manifests {
+            hasher.update(manifest.strip_prefix(&workspace_root)?.to_string_lossy().as_bytes());
+            hasher.update(std::fs::read(&manifest)?);
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    async fn send_build_unit(
+        &self,
+        unit: &BuildUnit,
+        server: &str,
+        refresh_metadata: bool,
+        send_dictionary: bool,
+    ) -> Result<()> {
+        let CachedTarball { source_sha256, compression: tarball_compression, data: tarball } =
+            self.source_tarball(unit)?;
+        let zstd_dictionary = if send_dictionary {
+            self.zstd_dictionary_for(&tarball_compression)?
+        } else {
+            None
+        };
+        self.save_source_archive(unit, &tarball, &tarball_compression).await?;
+
+        let _connection = match self.connections.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                debug!("Waiting for a free connection slot to build {}", unit.package_name);
+                self.connections.acquire().await?
+            }
+        };
+        let mut stream = self.connect(server).await?;
+
+        let (test_filter, test_ignored, test_nocapture) = match self.args.command {
+            Some(Command::Test { ref test_filter, test_ignored, test_nocapture }) => {
+                (test_filter.clone(), test_ignored, test_nocapture)
+            }
+            _ => (None, false, false),
+        };
+
+        let build_id = Uuid::new_v4();
+        let request = BuildRequest::BuildUnit {
+            build_id,
+            unit: unit.clone(),
+            command: self.cargo_command(),
+            release: self.args.release,
+            target: self.args.target.clone(),
+            jobs: self.args.server_jobs,
+            timings: self.args.timings,
+            auth_token: self.auth_token(server),
+            rustflags: self.rustflags(),
+            extern_crates: self.extern_crates(),
+            existing_artifact_hashes: self.existing_artifact_hashes(unit),
+            hash_algorithm: self.args.artifact_hash_algorithm,
+            cap_lints: self.args.cap_lints.clone(),
+            features: self.args.features.clone(),
+            extra_include_dirs: self.args.extra_include_dir.clone(),
+            build_std: self.args.build_std.clone(),
+            cargo_z_flags: self.args.cargo_unstable_flags.clone(),
+            emit_dep_info: self.args.emit_dep_info,
+            auto_remap: self.args.auto_remap,
+            check_cfg: self.check_cfg(unit),
+            memory_limit_mb: self.args.memory_limit_mb,
+            target_dir_server: self.args.target_dir_server.clone(),
+            incremental: self.incremental(),
+            check_unused_deps: self.args.error_on_unused_deps,
+            env_filter: self.args.env_filter.clone(),
+            use_sparse_registry: self.args.sparse_registry,
+            metadata_checksum: Self::metadata_checksum(unit)?,
+            vendored_lockfile: self.vendored_lockfile()?,
+            pinned_deps: self.pinned_deps()?,
+            env: self.forwarded_env()?,
+            crate_type_override: self.args.crate_type.clone(),
+            keep_workspace_on_error: self.args.no_clean_on_error,
+            chunked_artifacts: self.args.resumable_download,
+            compress_artifacts: self.args.compress_artifacts,
+            test_filter,
+            test_ignored,
+            test_nocapture,
+            refresh_metadata,
+            client_workspace_root: self.workspace_path.clone(),
+            tarball_compression,
+            zstd_dictionary,
+            tarball_data: tarball,
+        };
+
+        info!("Sending build request");
+        Self::send_request(&mut stream, &request).await?;
+
+        info!("Request sent, waiting for build stream");
+        self.handle_build_stream(stream, server, unit, build_id, &source_sha256).await?;
+
+        Ok(())
+    }
+
+    async fn build_unit_with_retries(&self, unit: BuildUnit, packages: &Semaphore) -> Result<()> {
+        let _permit = packages.acquire().await?;
+
+        // Fails with a helpful message when no server was given
+        self.server_addr()?;
+        let servers = &self.args.server;
+        let mut server_index = 0;
+        let mut server_attempts = 0;
+        let mut attempt = 1;
+        let mut rate_limit_wait = 0;
+        loop {
+            let server = &servers[server_index];
+            let e = match self.build_unit(unit.clone(), server, attempt).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+
+            // Rate limiting isn't a failure, so it doesn't use up a retry
+            if let Some(retry_after_secs) = e.chain().find_map(|c| c.downcast_ref::<RateLimited>()).map(|r| r.retry_after_secs) {
+                rate_limit_wait += retry_after_secs;
+                if rate_limit_wait > self.args.max_rate_limit_wait_secs {
+                    return Err(e.context(format!(
+                        "Gave up on {}: the server's rate limit would delay it more than {}s (--max-rate-limit-wait-secs)",
+                        unit.package_name, self.args.max_rate_limit_wait_secs
+                    )));
+                }
+                self.wait_for_rate_limit(&unit.package_name, retry_after_secs).await;
+                continue;
+            }
+
+            if attempt >= self.args.retries {
+                return Err(e.context(format!("Failed to build {} after {} attempts", unit.package_name, self.args.retries)));
+            }
+            // --retries caps the total; each server gets --max-retries-per-server in turn
+            server_attempts += 1;
+            if server_attempts >= self.args.max_retries_per_server {
+                server_index = (server_index + 1) % servers.len();
+                server_attempts = 0;
+            }
+            warn!(
+                "Build attempt {} failed for {}, retrying on {} in 2 seconds...",
+                attempt, unit.package_name, servers[server_index]
+            );
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            attempt += 1;
+        }
+    }
+
+    async fn wait_for_rate_limit(&self, package_name: &str, secs: u32) {
+        let bar = self.create_progress_bar("");
+        for remaining in (1..=secs).rev() {
+            bar.set_message(format!("{} rate limited by server, retrying in {}s", package_name, remaining).yellow().to_string());
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        bar.finish_and_clear();
+    }
+
+    fn build_local(&self) -> Result<()> {
+        let mut command = self.command("cargo");
+        command.arg(self.cargo_command().as_str());
+        if self.args.release {
+            command.arg("--release");
+        }
+        if let Some(ref target) = self.args.target {
+            command.args(["--target", target]);
+        }
+        if let Some(jobs) = self.args.server_jobs {
+            command.args(["--jobs", &jobs.to_string()]);
+        }
+        if !self.args.features.is_empty() {
+            command.args(["--features", &self.args.features.join(",")]);
+        }
+        if !self.args.build_std.is_empty() {
+            command.arg(format!("-Zbuild-std={}", self.args.build_std.join(",")));
+        }
+        for flag in &self.args.cargo_unstable_flags {
+            command.arg(format!("-Z{}", flag));
+        }
+        if let Some(incremental) = self.incremental() {
+            command.env("CARGO_INCREMENTAL", if incremental { "1" } else { "0" });
+        }
+
+        // Not the whole command, whose Debug output includes a forwarded client's environment
+        info!("Running local build: {:?} {:?}", command.get_program(), command.get_args().collect::<Vec<_>>());
+        let status = if FORWARDED_OUTPUT.try_with(|_| ()).is_ok() {
+            // The daemon's own stdio isn't the client's, so pass cargo's output along
+            let output = command.output().context("Failed to run local cargo build")?;
+            write_output(OutputStream::Stdout, String::from_utf8_lossy(&output.stdout).into_owned());
+            write_output(OutputStream::Stderr, String::from_utf8_lossy(&output.stderr).into_owned());
+            output.status
+        } else {
+            command.status().context("Failed to run local cargo build")?
+        };
+        if !status.success() {
+            return Err(anyhow::anyhow!("Local build failed with {}", status));
+        }
+        Ok(())
+    }
+
+    fn timing_report_path(&self) -> PathBuf {
+        self.workspace_path.join("target").join("cargo-timing.html")
+    }
+
+    fn open_timing_report(&self) {
+        let report_path = self.timing_report_path();
+        if !report_path.exists() {
+            warn!("No timing report was received from the server");
+            return;
+        }
+
+        let headless = cfg!(target_os = "linux") && self.env_var("DISPLAY").is_none();
+        if headless {
+            outln!("Timing report written to {}", report_path.display());
+        } else if let Err(e) = open::that(&report_path) {
+            warn!("Failed to open timing report: {}", e);
+            outln!("Timing report written to {}", report_path.display());
+        }
+    }
+
+    pub async fn build_single_unit(unit: BuildUnit, config: CliArgs) -> Result<()> {
+        let client = Self::new(config)?;
+        let packages = Semaphore::new(1);
+        client.build_unit_with_retries(unit, &packages).await
+    }
+
+    pub async fn build(&self) -> Result<()> {
+        if let Some(targets) = self.auto_detected_targets()? {
+            for target in targets {
+                info!("Building for target {}", target);
+                let mut args = self.args.clone();
+                args.target = Some(target.clone());
+                let client = Self::with_environment(args, self.workspace_path.clone(), self.client_env.clone())?;
+                *client.auth_tokens.lock().unwrap() = self.auth_tokens.lock().unwrap().clone();
+                Box::pin(client.build()).await
+                    .with_context(|| format!("Build for target {} failed", target))?;
+            }
+            return Ok(());
+        }
+
+        self.warning_count.store(0, Ordering::Relaxed);
+        self.seen_warnings.lock().unwrap().clear();
+        self.suppressed_warnings.store(0, Ordering::Relaxed);
+        self.built_artifacts.lock().await.clear();
+        let fingerprint = if self.args.diff_from_last {
+            let fingerprint = self.workspace_fingerprint()?;
+            self.print_fingerprint_diff(&fingerprint);
+            Some(fingerprint)
+        } else {
+            None
+        };
+        self.check_version_mismatches()?;
+        let result = self.build_with_fallback().await;
+        let suppressed = self.suppressed_warnings.load(Ordering::Relaxed);
+        if suppressed > 0 {
+            outln!("Suppressed {} duplicate warnings (pass --no-deduplicate-warnings to see all).", suppressed);
+        }
+        if result.is_ok() && self.args.timings && self.args.report_open {
+            self.open_timing_report();
+        }
+        result?;
+
+        if let Some(fingerprint) = fingerprint {
+            let path = self.last_fingerprint_path();
+            if let Err(e) = std::fs::create_dir_all(self.tesseract_dir())
+                .and_then(|_| std::fs::write(&path, serde_json::to_vec_pretty(&fingerprint)?))
+            {
+                warn!("Failed to save {}: {}", path.display(), e);
+            }
+        }
+
+        if !self.args.require_artifact.is_empty() {
+            let built = self.built_artifacts.lock().await;
+            for required in &self.args.require_artifact {
+                if !built.iter().flat_map(|(_, _, paths)| paths).any(|path| path.ends_with(required)) {
+                    return Err(anyhow::anyhow!(
+                        "Build succeeded but required artifact '{}' was not produced.",
+                        required.display()
+                    ));
+                }
+            }
+        }
+
+        if let Some(ref path) = self.args.checksum_file {
+            self.write_checksum_file(path).await
+                .with_context(|| format!("Failed to write checksum file {}", path.display()))?;
+        }
+
+        let warnings = self.warning_count.load(Ordering::Relaxed);
+        if self.args.deny_warnings && warnings > 0 {
+            return Err(anyhow::anyhow!(
+                "Build succeeded with {} warnings (treated as errors due to --deny-warnings)",
+                warnings
+            ));
+        }
+        Ok(())
+    }
+
+    /// Every artifact version this workspace has built, as <sha256>/<file name>
+    fn artifact_cache_dir(&self) -> PathBuf {
+        self.tesseract_dir().join("artifact-cache")
+    }
+
+    fn cache_artifacts(&self, paths: &[PathBuf]) -> Result<()> {
+        for path in paths {
+            let (Ok(data), Some(file_name)) = (std::fs::read(path), path.file_name()) else {
+                continue;
+            };
+            let hash: String = Sha256::digest(&data).iter().map(|b| format!("{:02x}", b)).collect();
+            let entry = self.artifact_cache_dir().join(&hash);
+            let cached = entry.join(file_name);
+            std::fs::create_dir_all(&entry)?;
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+            std::fs::write(entry.join(".last-used"), now.as_secs().to_string())?;
+            if cached.exists() {
+                continue;
+            }
+            // Artifacts are replaced by rename, never rewritten in place, so a hard link is safe
+            if std::fs::hard_link(path, &cached).is_err() {
+                std::fs::copy(path, &cached)?;
+            }
+            info!("Cached {} as {}", path.display(), &hash[..12]);
+        }
+        Ok(())
+    }
+
+    fn cached_artifact(&self, hash_prefix: &str, artifact: &str) -> Result<PathBuf> {
+        let mut matches: Vec<_> = std::fs::read_dir(self.artifact_cache_dir())
+            .context("The local artifact cache is empty")?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(hash_prefix))
+            .map(|entry| entry.path().join(artifact))
+            .filter(|path| path.is_file())
+            .collect();
+        match matches.len() {
+            1 => Ok(matches.remove(0)),
+            0 => Err(anyhow::anyhow!("No cached {} with a hash starting with {}", artifact, hash_prefix)),
+            n => Err(anyhow::anyhow!("{} cached builds of {} match {}; use a longer prefix", n, artifact, hash_prefix)),
+        }
+    }
+
+    fn diff_artifacts(&self, before: &str, after: &str, artifact: &str) -> Result<()> {
+        let before = self.cached_artifact(before, artifact)?;
+        let after = self.cached_artifact(after, artifact)?;
+
+        let (before_size, after_size) = (std::fs::metadata(&before)?.len(), std::fs::metadata(&after)?.len());
+        outln!(
+            "{}: {} -> {} bytes ({:+})",
+            artifact.bold(),
+            before_size,
+            after_size,
+            after_size as i64 - before_size as i64
+        );
+
+        let status = match std::process::Command::new("diffoscope").arg(&before).arg(&after).status() {
+            Ok(status) => status,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("diffoscope not found, comparing disassembly with objdump");
+                let disassemble = |path: &Path| -> Result<tempfile::NamedTempFile> {
+                    let output = std::process::Command::new("objdump")
+                        .arg("--disassemble")
+                        .arg(path)
+                        .output()
+                        .context("Failed to run objdump; install diffoscope or binutils")?;
+                    if !output.status.success() {
+                        return Err(anyhow::anyhow!(
+                            "objdump failed on {}: {}",
+                            path.display(),
+                            String::from_utf8_lossy(&output.stderr).trim()
+                        ));
+                    }
+                    let mut file = tempfile::NamedTempFile::new()?;
+                    file.write_all(&output.stdout)?;
+                    Ok(file)
+                };
+                let (before, after) = (disassemble(&before)?, disassemble(&after)?);
+                std::process::Command::new("diff")
+                    .arg("-u")
+                    .arg(before.path())
+                    .arg(after.path())
+                    .status()
+                    .context("Failed to run diff")?
+            }
+            Err(e) => return Err(e).context("Failed to run diffoscope"),
+        };
+
+        // Both tools exit with 1 when the files differ
+        match status.code() {
+            Some(0 | 1) => Ok(()),
+            _ => Err(anyhow::anyhow!("Comparing artifacts failed with {}", status)),
+        }
+    }
+
+    /// Artifact paths are relative to the workspace, so `sha256sum -c` works from there
+    async fn write_checksum_file(&self, path: &Path) -> Result<()> {
+        let built = self.built_artifacts.lock().await;
+        let mut manifest = format!(
+            "# built {}\n",
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        );
+        for (package_name, source_sha256, _) in built.iter() {
+            manifest.push_str(&format!("# source {} {}\n", package_name, source_sha256));
+        }
+        for artifact in built.iter().flat_map(|(_, _, paths)| paths) {
+            let hash: String = Sha256::digest(std::fs::read(artifact)?)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            let relative = artifact.strip_prefix(&self.workspace_path).unwrap_or(artifact);
+            manifest.push_str(&format!("{}  {}\n", hash, relative.display()));
+        }
+        std::fs::write(path, manifest)?;
+        info!("Wrote checksums to {}", path.display());
+        Ok(())
+    }
+
+    fn verify_checksums(&self, path: &Path) -> Result<()> {
+        let manifest = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        let mut failures = 0;
+        for line in manifest.lines().filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let (expected, artifact) = line
+                .split_once("  ")
+                .ok_or_else(|| anyhow::anyhow!("Malformed checksum line: {}", line))?;
+            let actual: Option<String> = std::fs::read(self.workspace_path.join(artifact))
+                .ok()
+                .map(|data| Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect());
+            match actual {
+                Some(actual) if actual == expected => outln!("{}: {}", artifact, "OK".green()),
+                Some(_) => {
+                    outln!("{}: {}", artifact, "FAILED".red().bold());
+                    failures += 1;
+                }
+                None => {
+                    outln!("{}: {}", artifact, "MISSING".red().bold());
+                    failures += 1;
+                }
+            }
+        }
+
+        if failures > 0 {
+            return Err(anyhow::anyhow!("{} artifacts did not match {}", failures, path.display()));
+        }
+        Ok(())
+    }
+
+    async fn verify_install(&self, require: &[ToolSpec]) -> Result<()> {
+        let mut required_tools: Vec<ToolSpec> = ["cargo", "rustc", "clippy", "rustfmt", "cargo-audit", "wasm-pack"]
+            .into_iter()
+            .filter(|name| !require.iter().any(|tool| tool.name == *name))
+            .map(|name| ToolSpec { name: name.to_string(), min_version: None })
+            .collect();
+        required_tools.extend(require.iter().cloned());
+
+        let mut stream = self.connect(self.server_addr()?).await?;
+        Self::send_request(&mut stream, &BuildRequest::VerifyInstall {
+            required_tools,
+            auth_token: self.auth_token(self.server_addr()?),
+        }).await?;
+        let (available, missing) = match Self::read_response(&mut stream).await? {
+            BuildResponse::ToolsStatus { available, missing } => (available, missing),
+            BuildResponse::BuildError { error, .. } => {
+                return Err(anyhow::anyhow!("Checking server tools failed: {}", error));
+            }
+            other => return Err(anyhow::anyhow!("Unexpected response to verify-install: {:?}", other)),
+        };
+
+        let width = available.iter().chain(&missing).map(|tool| tool.to_string().len()).max().unwrap_or(0);
+        outln!("{:<width$}  {}", "TOOL".bold(), "STATUS".bold(), width = width);
+        for tool in &available {
+            outln!("{:<width$}  {}", tool.to_string(), "installed".green(), width = width);
+        }
+        for tool in &missing {
+            let status = if require.iter().any(|r| r.name == tool.name) { "missing".red().bold() } else { "missing".yellow() };
+            outln!("{:<width$}  {}", tool.to_string(), status, width = width);
+        }
+
+        let missing_required: Vec<_> = missing
+            .iter()
+            .filter(|tool| require.iter().any(|r| r.name == tool.name))
+            .map(|tool| tool.to_string())
+            .collect();
+        if !missing_required.is_empty() {
+            return Err(anyhow::anyhow!("Required tools missing on the server: {}", missing_required.join(", ")));
+        }
+        Ok(())
+    }
+
+    async fn diagnose(&self) -> Result<()> {
+        let mut failures = 0;
+        let mut report = |result: Result<String, String>, warning: bool| match result {
+            Ok(message) => outln!("{} {}", "[OK]  ".green().bold(), message),
+            Err(message) if warning => outln!("{} {}", "[WARN]".yellow().bold(), message),
+            Err(message) => {
+                outln!("{} {}", "[FAIL]".red().bold(), message);
+                failures += 1;
+            }
+        };
+
+        if self.args.server.is_empty() {
+            report(Err("No server configured; pass --server or set TESSERACT_SERVER".to_string()), false);
+        }
+        for server in &self.args.server {
+            let connect = tokio::time::timeout(Duration::from_secs(5), self.connect(server)).await;
+            report(match connect {
+                Ok(Ok(_)) => Ok(format!("Server {} is reachable", server)),
+                Ok(Err(e)) => Err(format!("Server {} is unreachable: {:#}", server, e)),
+                Err(_) => Err(format!("Server {} did not accept a connection within 5s", server)),
+            }, false);
+        }
+
+        let metadata = match self.workspace_metadata() {
+            Ok(metadata) => {
+                report(Ok(format!("Workspace at {} has {} packages", metadata.workspace_root, metadata.packages.len())), false);
+                Some(metadata)
+            }
+            Err(e) => {
+                report(Err(format!("Could not read the workspace's Cargo.toml: {:#}", e)), false);
+                None
+            }
+        };
+
+        if let Some(ref metadata) = metadata {
+            let workspace_root = metadata.workspace_root.as_std_path();
+            let ignore_patterns = Self::read_gitignore(workspace_root);
+            let ignored_sources: Vec<_> = metadata
+                .packages
+                .iter()
+                .flat_map(|package| &package.targets)
+                .filter(|target| Self::is_ignored(target.src_path.as_std_path(), workspace_root, &ignore_patterns))
+                .map(|target| target.src_path.to_string())
+                .collect();
+            report(if ignored_sources.is_empty() {
+                Ok("No target sources are excluded by .gitignore or .tesseractignore".to_string())
+            } else {
+                Err(format!("These sources are ignored and won't be uploaded: {}", ignored_sources.join(", ")))
+            }, false);
+
+            let limit = self.args.max_tarball_size_mb * 1024 * 1024;
+            match self.discover_build_units() {
+                Ok(units) => {
+                    for unit in units {
+                        let size = self
+                            .create_tarball(&unit)
+                            .and_then(|tar| self.compress_tarball(&tar))
+                            .map(|(tarball, _)| tarball.len() as u64);
+                        report(match size {
+                            Ok(size) if limit == 0 || size <= limit => {
+                                Ok(format!("{} source tarball is {} KB", unit.package_name, size / 1024))
+                            }
+                            Ok(size) => Err(format!(
+                                "{} source tarball is {} MB, over the {} MB --max-tarball-size-mb limit",
+                                unit.package_name,
+                                size / (1024 * 1024),
+                                self.args.max_tarball_size_mb
+                            )),
+                            Err(e) => Err(format!("Could not create the {} source tarball: {:#}", unit.package_name, e)),
+                        }, false);
+                    }
+                }
+                Err(e) => report(Err(format!("Could not discover build units: {:#}", e)), false),
+            }
+        }
+
+        if let Some(ref target) = self.args.target {
+            let output = |program: &str, args: &[&str]| {
+                std::process::Command::new(program)
+                    .args(args)
+                    .output()
+                    .ok()
+                    .filter(|output| output.status.success())
+                    .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+            };
+            let known = output("rustc", &["--print", "target-list"]);
+            let installed = output("rustup", &["target", "list", "--installed"]);
+            match (known, installed) {
+                (Some(known), _) if !known.lines().any(|line| line == target) => {
+                    report(Err(format!("{} is not a target rustc knows", target)), false)
+                }
+                (_, Some(installed)) if installed.lines().any(|line| line == target) => {
+                    report(Ok(format!("Target {} is installed locally", target)), false)
+                }
+                (_, Some(_)) => report(
+                    Err(format!("Target {} is valid but not installed locally (rustup target add {})", target, target)),
+                    true,
+                ),
+                (_, None) => report(Err("Could not run rustup to check the target".to_string()), true),
+            }
+        }
+
+        if failures > 0 {
+            return Err(anyhow::anyhow!("{} checks failed", failures));
+        }
+        Ok(())
+    }
+
+    async fn build_and_run(&self) -> Result<()> {
+        let metadata = self.workspace_metadata()?;
+        let mut binaries: Vec<_> = metadata
+            .packages
+            .iter()
+            .flat_map(|package| package.targets.iter().map(move |target| (package, target)))
+            .filter(|(_, target)| target.kind.iter().any(|k| k == "bin"))
+            .filter(|(_, target)| self.args.bin.as_ref().is_none_or(|bin| *bin == target.name))
+            .map(|(package, target)| (package.name.to_string(), target.name.clone()))
+            .collect();
+        let (package_name, bin_name) = match (binaries.len(), &self.args.bin) {
+            (1, _) => binaries.remove(0),
+            (0, Some(bin)) => return Err(anyhow::anyhow!("No binary target named {} in the workspace", bin)),
+            (0, None) => return Err(anyhow::anyhow!("--run needs a binary target, but the workspace has none")),
+            _ => return Err(anyhow::anyhow!("The workspace has several binaries; choose one with --bin")),
+        };
+
+        self.build().await.context("Build failed")?;
+
+        let unit = self
+            .discover_build_units_filtered(&UnitFilter {
+                packages: vec![package_name.clone()],
+                ..UnitFilter::default()
+            })?
+            .into_iter()
+            .next()
+            .with_context(|| format!("Package {} disappeared from the workspace", package_name))?;
+        let artifacts: Vec<PathBuf> = std::fs::read(self.artifact_manifest_path(&package_name))
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default();
+        let binary = artifacts
+            .iter()
+            .map(|path| self.local_artifact_path(&unit, path))
+            .find(|path| {
+                path.file_stem().is_some_and(|stem| *stem == *bin_name)
+                    && path.extension().is_none_or(|ext| ext == "exe")
+            })
+            .with_context(|| format!("The server did not return a binary for {}", bin_name))?;
+
+        info!("Running {}", binary.display());
+        let status = self.command(&binary)
+            .args(&self.args.exec_args)
+            .status()
+            .with_context(|| format!("Failed to run {}", binary.display()))?;
+        match status.code() {
+            Some(0) => Ok(()),
+            Some(code) => Err(ChildExit { code }.into()),
+            None => Err(anyhow::anyhow!("{} was terminated by a signal", binary.display())),
+        }
+    }
+
+    fn auto_detected_targets(&self) -> Result<Option<Vec<String>>> {
+        if !self.args.auto_detect_targets || self.args.target.is_some() {
+            return Ok(None);
+        }
+
+        let path = self.workspace_path.join("rust-toolchain.toml");
+        if !path.exists() {
+            warn!("--auto-target given but {} does not exist", path.display());
+            return Ok(None);
+        }
+
+        #[derive(Deserialize)]
+        struct ToolchainFile {
+            toolchain: Toolchain,
+        }
+        #[derive(Deserialize)]
+        struct Toolchain {
+            #[serde(default)]
+            targets: Vec<String>,
+        }
+
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let file: ToolchainFile = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        Ok((!file.toolchain.targets.is_empty()).then_some(file.toolchain.targets))
+    }
+
+    fn watch_excludes(&self) -> Result<globset::GlobSet> {
+        let mut builder = globset::GlobSetBuilder::new();
+        for pattern in WATCH_EXCLUDE_DEFAULTS.iter().copied().chain(self.args.watch_exclude.iter().map(String::as_str)) {
+            builder.add(
+                globset::Glob::new(pattern)
+                    .with_context(|| format!("Invalid --watch-exclude pattern '{}'", pattern))?,
+            );
+        }
+        Ok(builder.build()?)
+    }
+
+    fn is_watch_trigger(&self, event: &notify::Event, excludes: &globset::GlobSet) -> bool {
+        !matches!(event.kind, notify::EventKind::Access(_))
+            && event.paths.iter().any(|path| {
+                let relative = path.strip_prefix(&self.workspace_path).unwrap_or(path);
+                !excludes.is_match(relative)
+            })
+    }
+
+    pub async fn watch(&self) -> Result<()> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if let Ok(event) = event {
+                let _ = tx.send(event);
+            }
+        })?;
+        watcher.watch(&self.workspace_path, notify::RecursiveMode::Recursive)?;
+        let excludes = self.watch_excludes()?;
+
+        loop {
+            match self.build().await {
+                Err(e) if self.args.reconnect_on_server_restart && Self::is_disconnect(&e) => {
+                    warn!("{:#}", e);
+                    outln!("{}", "Server disconnected, waiting for it to come back...".yellow());
+                    self.wait_for_server().await?;
+                    outln!("{}", "Reconnected to server, resuming watch...".green());
+                    // The restart may have been an update, so check the version again
+                    self.version_checked.store(false, Ordering::Relaxed);
+                    continue;
+                }
+                Err(e) => error!("Build failed: {:#}", e),
+                Ok(()) => {}
+            }
+            outln!("{}", "Watching for changes...".cyan());
+
+            let mut changed = Vec::new();
+            while changed.is_empty() {
+                let event = rx.recv().await.context("File watcher stopped")?;
+                if self.is_watch_trigger(&event, &excludes) {
+                    changed.extend(event.paths);
+                }
+            }
+            while let Ok(Some(event)) = tokio::time::timeout(WATCH_DEBOUNCE, rx.recv()).await {
+                if self.is_watch_trigger(&event, &excludes) {
+                    changed.extend(event.paths);
+                }
+            }
+
+            if changed.iter().any(|path| path.ends_with("Cargo.toml") || path.ends_with("Cargo.lock")) {
+                info!("Manifest changed, invalidating metadata cache");
+                let _ = std::fs::remove_file(self.metadata_cache_path(false));
+                let _ = std::fs::remove_file(self.metadata_cache_path(true));
+            }
+            info!("Detected {} changed paths, rebuilding", changed.len());
+        }
+    }
+
+    fn is_disconnect(e: &anyhow::Error) -> bool {
+        use std::io::ErrorKind;
+        e.chain().any(|cause| cause.is::<ServerUnreachable>())
+            || e.root_cause().downcast_ref::<std::io::Error>().is_some_and(|e| {
+                matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof
+                )
+            })
+    }
+
+    /// Polls the servers with exponential backoff until one accepts a connection.
+    async fn wait_for_server(&self) -> Result<()> {
+        let timeout = Duration::from_secs(self.args.watch_reconnect_timeout_secs);
+        let started = std::time::Instant::now();
+        let spinner = self.create_progress_bar("Waiting for server...");
+        let mut delay = Duration::from_secs(1);
+        loop {
+            for server in &self.args.server {
+                if self.connect(server).await.is_ok() {
+                    spinner.finish_and_clear();
+                    return Ok(());
+                }
+            }
+            let elapsed = started.elapsed();
+            if elapsed >= timeout {
+                spinner.finish_and_clear();
+                return Err(anyhow::anyhow!(
+                    "Server did not come back within {}s (--watch-reconnect-timeout-secs)",
+                    timeout.as_secs()
+                ));
+            }
+            spinner.set_message(format!("Waiting for server... ({}s)", elapsed.as_secs()));
+            tokio::time::sleep(delay.min(timeout - elapsed)).await;
+            delay = (delay * 2).min(Duration::from_secs(30));
+        }
+    }
+
+    async fn build_with_fallback(&self) -> Result<()> {
+        match self.build_remote().await {
+            Err(e) if self.args.fallback_local && e.chain().any(|cause| cause.is::<ServerUnreachable>()) => {
+                warn!("Remote build failed: {:#}", e);
+                outln!("{}", "Server unreachable, building locally".yellow());
+                self.build_local()?;
+                info!("Build completed locally (fallback from {})", self.server_addr()?);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    async fn save_source_archive(&self, unit: &BuildUnit, tarball: &[u8], compression: &TarballCompression) -> Result<()> {
+        let Some(ref path) = self.args.source_archive else {
+            return Ok(());
+        };
+        let path = if path.is_dir() {
+            let extension = match compression {
+                TarballCompression::Gzip => "tar.gz",
+                TarballCompression::Zstd { .. } => "tar.zst",
+            };
+            path.join(format!("{}.{}", unit.package_name, extension))
+        } else {
+            path.clone()
+        };
+        Self::write_artifact_safely(&path, tarball).await
+            .with_context(|| format!("Failed to write source archive {}", path.display()))?;
+        info!("Saved source archive for {} to {}", unit.package_name, path.display());
+        Ok(())
+    }
+
+    async fn dry_run(&self) -> Result<()> {
+        for unit in self.discover_build_units()? {
+            let tarball = self.source_tarball(&unit)?;
+            self.save_source_archive(&unit, &tarball.data, &tarball.compression).await?;
+            let compression = match tarball.compression {
+                TarballCompression::Gzip => "gzip",
+                TarballCompression::Zstd { .. } => "zstd",
+            };
+            outln!(
+                "{} {} {} ({} byte {} tarball)",
+                "Would build".cyan().bold(),
+                unit.package_name,
+                unit.version,
+                tarball.data.len(),
+                compression
+            );
+        }
+        Ok(())
+    }
+
+    async fn build_remote(&self) -> Result<()> {
+        self.progress.lock().await.clear();
+
+        if !self.args.skip_version_check && !self.version_checked.load(Ordering::Relaxed) {
+            self.check_server_version().await?;
+            self.version_checked.store(true, Ordering::Relaxed);
+        }
+
+        info!("Discovering build units in workspace...");
+        let units = self.discover_build_units()?;
+        info!("Found {} build units", units.len());
+
+        let packages = Semaphore::new(self.args.parallel_packages as usize);
+        // Units wait for the workspace proc macros they use before taking a package slot
+        let proc_macros: HashMap<String, tokio::sync::watch::Sender<Option<bool>>> = units
+            .iter()
+            .filter(|unit| unit.is_proc_macro)
+            .map(|unit| (unit.package_name.clone(), tokio::sync::watch::channel(None).0))
+            .collect();
+        try_join_all(units.into_iter().map(|unit| {
+            let (proc_macros, packages) = (&proc_macros, &packages);
+            async move {
+                for dependency in &unit.proc_macro_deps {
+                    let Some(built) = proc_macros.get(dependency) else {
+                        continue;
+                    };
+                    let mut built = built.subscribe();
+                    if *built.wait_for(Option::is_some).await? != Some(true) {
+                        return Err(anyhow::anyhow!("Proc macro {} failed to build", dependency));
+                    }
+                }
+                let package_name = unit.package_name.clone();
+                let result = self.build_unit_with_retries(unit, packages).await;
+                if let Some(built) = proc_macros.get(&package_name) {
+                    built.send_replace(Some(result.is_ok()));
+                }
+                result
+            }
+        }))
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+async fn write_framed_json<T: Serialize>(stream: &mut (impl tokio::io::AsyncWrite + Unpin), value: &T) -> Result<()> {
+    let data = serde_json::to_vec(value)?;
+    stream.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    stream.write_all(&data).await?;
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn read_framed_json<T: serde::de::DeserializeOwned>(stream: &mut (impl tokio::io::AsyncRead + Unpin)) -> Result<T> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+    let mut data = vec![0u8; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut data).await?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    fn parse_args(args: &[&str]) -> CliArgs {
+        let Cargo::Tesseract(args) = Cargo::try_parse_from(["cargo", "tess"].iter().chain(args)).unwrap();
+        args
+    }
+
+    /// A workspace of empty library packages
+    fn workspace(members: &[&str]) -> tempfile::TempDir {
+        let workspace = tempfile::tempdir().unwrap();
+        std::fs::write(workspace.path().join("Cargo.toml"), format!("[workspace]\nmembers = {:?}\n", members)).unwrap();
+        for member in members {
+            let dir = workspace.path().join(member);
+            std::fs::create_dir_all(dir.join("src")).unwrap();
+            std::fs::write(
+                dir.join("Cargo.toml"),
+                format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n", member),
+            )
+            .unwrap();
+            std::fs::write(dir.join("src").join("lib.rs"), "").unwrap();
+        }
+        workspace
+    }
+
+    #[tokio::test]
+    async fn parallel_packages_bounds_concurrent_builds() {
+        let workspace = workspace(&["a", "b", "c", "d"]);
+
+        // Holds each build for a while, then hangs up so it fails
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let jobs = Arc::new(std::sync::Mutex::new(Vec::new()));
+        tokio::spawn({
+            let (in_flight, max_in_flight, jobs) = (in_flight.clone(), max_in_flight.clone(), jobs.clone());
+            async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let (in_flight, max_in_flight, jobs) = (in_flight.clone(), max_in_flight.clone(), jobs.clone());
+                    tokio::spawn(async move {
+                        let mut len = [0u8; 4];
+                        stream.read_exact(&mut len).await.unwrap();
+                        let mut data = vec![0u8; u32::from_be_bytes(len) as usize];
+                        stream.read_exact(&mut data).await.unwrap();
+                        if let BuildRequest::BuildUnit { jobs: requested, .. } = bincode::deserialize(&data).unwrap() {
+                            jobs.lock().unwrap().push(requested);
+                        }
+                        let building = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_in_flight.fetch_max(building, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(300)).await;
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+            }
+        });
+
+        let args = parse_args(&[
+            "--server",
+            &server,
+            "--parallel-packages",
+            "2",
+            "--server-jobs",
+            "8",
+            "--max-concurrent-connections",
+            "16",
+            "--retries",
+            "1",
+            "--skip-version-check",
+            "--auth-token",
+            "token",
+        ]);
+        let client = TesseractClient::with_environment(args, workspace.path().to_path_buf(), None).unwrap();
+        assert!(client.build_remote().await.is_err());
+
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+        let jobs = jobs.lock().unwrap();
+        assert!(!jobs.is_empty());
+        assert!(jobs.iter().all(|jobs| *jobs == Some(8)), "{:?}", jobs);
+    }
+
+    #[test]
+    fn classify_build_errors() {
+        assert_eq!(BuildErrorKind::classify(Some(101), "error: No space left on device"), BuildErrorKind::DiskFull);
+        assert_eq!(BuildErrorKind::classify(Some(137), ""), BuildErrorKind::OutOfMemory);
+        assert_eq!(
+            BuildErrorKind::classify(Some(101), "memory allocation of 1024 bytes failed"),
+            BuildErrorKind::OutOfMemory
+        );
+        assert_eq!(
+            BuildErrorKind::classify(Some(101), "error: linking with `cc` failed: exit status: 1"),
+            BuildErrorKind::LinkerError
+        );
+        assert_eq!(
+            BuildErrorKind::classify(Some(101), "error[E0308]: mismatched types\nerror: could not compile `app`"),
+            BuildErrorKind::CompileError
+        );
+        assert_eq!(BuildErrorKind::classify(None, ""), BuildErrorKind::Timeout);
+        assert_eq!(BuildErrorKind::classify(Some(1), "something else"), BuildErrorKind::InternalServerError);
+    }
+
+    #[test]
+    fn artifact_file_names() {
+        let name = |os: ArtifactOs, path: &str| os.cargo_file_name(Path::new(path));
+        assert_eq!(name(ArtifactOs::Windows, "target/debug/app"), PathBuf::from("target/debug/app.exe"));
+        assert_eq!(name(ArtifactOs::Unix, "target/debug/app.exe"), PathBuf::from("target/debug/app"));
+        assert_eq!(name(ArtifactOs::Windows, "libfoo.so"), PathBuf::from("foo.dll"));
+        assert_eq!(name(ArtifactOs::MacOs, "libfoo.so"), PathBuf::from("libfoo.dylib"));
+        assert_eq!(name(ArtifactOs::Unix, "foo.dll"), PathBuf::from("libfoo.so"));
+        assert_eq!(name(ArtifactOs::Windows, "libfoo.a"), PathBuf::from("foo.lib"));
+        assert_eq!(name(ArtifactOs::MacOs, "foo.lib"), PathBuf::from("libfoo.a"));
+        assert_eq!(name(ArtifactOs::Unix, "foo.rlib"), PathBuf::from("foo.rlib"));
+    }
+
+    /// Boolean flags set from the environment must accept `1`, as in `TESSERACT_RELEASE=1`
+    #[test]
+    fn env_flags_accept_boolish_values() {
+        let cargo = Cargo::command();
+        let tess = cargo.find_subcommand("tess").unwrap();
+        let flags: Vec<_> = tess
+            .get_arguments()
+            .filter(|arg| arg.get_env().is_some() && matches!(arg.get_action(), clap::ArgAction::SetTrue))
+            .map(|arg| (arg.get_id().clone(), arg.get_long().unwrap().to_string()))
+            .collect();
+        assert!(flags.iter().any(|(_, long)| long == "release"));
+
+        for (id, long) in flags {
+            // Parse the flag as if it took a value, which runs the same value parser as the env lookup
+            let command = tess.clone().mut_arg(id, |arg| arg.action(clap::ArgAction::Set).num_args(1));
+            for value in ["1", "0", "true", "yes", "off"] {
+                let result = command.clone().try_get_matches_from(["tess", "--server", "h:1", &format!("--{}={}", long, value)]);
+                if let Err(e) = result {
+                    assert_ne!(e.kind(), clap::error::ErrorKind::InvalidValue, "--{}={}: {}", long, value, e);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn s3_destination() {
+        let dest = S3Destination::parse("s3://bucket/builds/nightly/").unwrap();
+        assert_eq!(dest.bucket, "bucket");
+        assert_eq!(dest.prefix, "builds/nightly");
+        assert_eq!(dest.key(Path::new("release/app")), "builds/nightly/release/app");
+
+        let dest = S3Destination::parse("s3://bucket").unwrap();
+        assert_eq!(dest.prefix, "");
+        assert_eq!(dest.key(Path::new("app")), "app");
+
+        assert!(S3Destination::parse("bucket/prefix").is_err());
+        assert!(S3Destination::parse("s3:///prefix").is_err());
+    }
+
+    #[test]
+    fn key_value() {
+        assert_eq!(parse_key_value("A=1"), Ok(("A".to_string(), "1".to_string())));
+        assert_eq!(parse_key_value("A=b=c"), Ok(("A".to_string(), "b=c".to_string())));
+        assert_eq!(parse_key_value("A="), Ok(("A".to_string(), String::new())));
+        assert!(parse_key_value("=1").is_err());
+        assert!(parse_key_value("A").is_err());
+    }
+
+    #[test]
+    fn target_feature() {
+        assert_eq!(parse_target_feature("+avx2"), Ok("+avx2".to_string()));
+        assert_eq!(parse_target_feature("-sse4.1"), Ok("-sse4.1".to_string()));
+        assert_eq!(parse_target_feature("+crt-static"), Err("expected +FEATURE or -FEATURE, got '+crt-static'".to_string()));
+        assert!(parse_target_feature("avx2").is_err());
+        assert!(parse_target_feature("+").is_err());
+    }
+
+    #[test]
+    fn validate_server_addresses() {
+        assert!(parse_args(&["--server", "build.example.com:7000"]).validate().is_ok());
+        assert!(parse_args(&["--server", "a:1,b:2"]).validate().is_ok());
+        for server in ["build.example.com", ":7000", "host:0", "host:70000", "host:port"] {
+            assert!(parse_args(&["--server", server]).validate().is_err(), "{} should be rejected", server);
+        }
+    }
+
+    #[test]
+    fn validate_retries_and_target() {
+        assert!(parse_args(&["--server", "h:1", "--retries", "0"]).validate().is_err());
+        assert!(parse_args(&["--server", "h:1", "--target", "x86_64-unknown-linux-gnu"]).validate().is_ok());
+        assert!(parse_args(&["--server", "h:1", "--target", "custom/my-target.json"]).validate().is_ok());
+        assert!(parse_args(&["--server", "h:1", "--target", "not a triple"]).validate().is_err());
+
+        let errors = parse_args(&["--server", "nohost", "--retries", "0"]).validate().unwrap_err().to_string();
+        assert_eq!(errors.lines().count(), 2, "{}", errors);
+    }
+
+    #[test]
+    fn tool_spec() {
+        let tool = ToolSpec::parse("protoc").unwrap();
+        assert_eq!(tool.name, "protoc");
+        assert_eq!(tool.min_version, None);
+
+        let tool = ToolSpec::parse("cmake@3.20.0").unwrap();
+        assert_eq!(tool.name, "cmake");
+        assert_eq!(tool.min_version, Some(cargo_metadata::semver::Version::new(3, 20, 0)));
+        assert_eq!(tool.to_string(), "cmake >= 3.20.0");
+
+        assert!(ToolSpec::parse("cmake@3").is_err());
+        assert!(ToolSpec::parse("@1.0.0").is_err());
+        assert!(ToolSpec::parse("").is_err());
+    }
+
+    #[test]
+    fn period_units() {
+        assert_eq!(parse_period("90s"), Ok(90));
+        assert_eq!(parse_period("30m"), Ok(30 * 60));
+        assert_eq!(parse_period("24h"), Ok(24 * 60 * 60));
+        assert_eq!(parse_period("12"), Ok(12 * 60 * 60));
+        assert_eq!(parse_period("7d"), Ok(7 * 24 * 60 * 60));
+        assert_eq!(parse_period("2w"), Ok(2 * 7 * 24 * 60 * 60));
+        assert!(parse_period("h").is_err());
+        assert!(parse_period("").is_err());
+        assert!(parse_period("5y").is_err());
+        assert!(parse_period("-5h").is_err());
+    }
+
+    #[test]
+    fn validate_cargo_unstable_flags() {
+        assert!(parse_args(&["--server", "h:1", "--cargo-unstable-flags=build-std"]).validate().is_ok());
+        assert!(parse_args(&["--server", "h:1", "--cargo-unstable-flags=-Zbuild-std"]).validate().is_err());
+        assert!(parse_args(&["--server", "h:1", "--cargo-unstable-flags="]).validate().is_err());
+    }
+
+    #[test]
+    fn validate_inject_env_file() {
+        let dotenv = tempfile::NamedTempFile::new().unwrap();
+        assert!(parse_args(&["--server", "h:1", "--inject-env-from-file", dotenv.path().to_str().unwrap()]).validate().is_ok());
+        assert!(parse_args(&["--server", "h:1", "--inject-env-from-file", "/nonexistent/.env"]).validate().is_err());
+    }
+}